@@ -0,0 +1,110 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+// Kernel arguments which name a datasource outright, highest precedence first; the cloud-init
+// namespaced forms win over the bare ones
+const DATASOURCE_KEYS: &[&[&str]] = &[&["ci.ds", "ci.datasource"], &["ds", "datasource"]];
+
+// A datasource named on the kernel command line, e.g. "ds=nocloud;s=http://10.0.0.1/"
+pub struct DatasourceArg {
+    pub name: String,
+    pub options: Vec<(String, String)>,
+}
+
+pub fn find_datasource_arg(cmdline: &str) -> Option<DatasourceArg> {
+    DATASOURCE_KEYS.iter().find_map(|keys| {
+        // As with any other kernel argument, the last occurrence wins
+        cmdline
+            .split_whitespace()
+            .filter_map(|token| parse_datasource_token(token, keys))
+            .next_back()
+    })
+}
+
+fn parse_datasource_token(token: &str, keys: &[&str]) -> Option<DatasourceArg> {
+    let (key, value) = token.split_once('=')?;
+    if !keys.contains(&key) {
+        return None;
+    }
+    let mut parts = value.split(';');
+    let name = parts.next().filter(|name| !name.is_empty())?.to_string();
+    let options = parts
+        .filter_map(|option| option.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Some(DatasourceArg { name, options })
+}
+
+// The state requested by "cloud-init=enabled" or "cloud-init=disabled"
+#[derive(PartialEq)]
+pub enum CloudInitArg {
+    Enabled,
+    Disabled,
+}
+
+pub fn find_cloud_init_arg(cmdline: &str) -> Option<CloudInitArg> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|token| match token {
+            "cloud-init=enabled" => Some(CloudInitArg::Enabled),
+            "cloud-init=disabled" => Some(CloudInitArg::Disabled),
+            _ => None,
+        })
+        .next_back()
+}
+
+// A detection policy given as "ci.di.policy=<policy>", e.g. "ci.di.policy=search,found=first"
+pub fn find_policy_arg(cmdline: &str) -> Option<&str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|token| token.strip_prefix("ci.di.policy="))
+        .next_back()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datasource_name(cmdline: &str) -> Option<String> {
+        find_datasource_arg(cmdline).map(|datasource_arg| datasource_arg.name)
+    }
+
+    #[test]
+    fn namespaced_keys_beat_bare_ones() {
+        assert_eq!(
+            datasource_name("ci.ds=Ec2 ds=nocloud").as_deref(),
+            Some("Ec2")
+        );
+        assert_eq!(
+            datasource_name("ds=nocloud ci.ds=Ec2").as_deref(),
+            Some("Ec2")
+        );
+        assert_eq!(
+            datasource_name("datasource=nocloud ci.datasource=Ec2").as_deref(),
+            Some("Ec2")
+        );
+    }
+
+    #[test]
+    fn last_occurrence_wins_within_a_key_group() {
+        assert_eq!(
+            datasource_name("ci.ds=Ec2 ci.datasource=GCE").as_deref(),
+            Some("GCE")
+        );
+        assert_eq!(
+            datasource_name("ds=nocloud datasource=Azure ds=OpenStack").as_deref(),
+            Some("OpenStack")
+        );
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let datasource_arg = find_datasource_arg("ds=nocloud;s=http://10.0.0.1/").unwrap();
+        assert_eq!(datasource_arg.name, "nocloud");
+        assert_eq!(
+            datasource_arg.options,
+            vec![("s".to_string(), "http://10.0.0.1/".to_string())]
+        );
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process::Command;
+
+// A block device, as far as datasource checks are concerned
+pub struct BlockDevice {
+    pub devname: String,
+    pub label: Option<String>,
+    pub fs_type: Option<String>,
+    pub partlabel: Option<String>,
+}
+
+// blkid
+pub fn scan_blkid(blkid: &Path) -> Option<Vec<BlockDevice>> {
+    // Bypass the blkid cache so that devices attached since it was written aren't missed
+    let output = Command::new(blkid)
+        .args(["-c", "/dev/null", "-o", "export"])
+        .output()
+        .ok()?;
+    match output.status.code() {
+        Some(0) => Some(parse_blkid_export(&String::from_utf8_lossy(&output.stdout))),
+        // blkid exits 2 when there are no devices with any tags to report
+        Some(2) => Some(vec![]),
+        _ => None,
+    }
+}
+
+// `blkid -o export` emits a blank-line-separated KEY=value stanza per device
+fn parse_blkid_export(output: &str) -> Vec<BlockDevice> {
+    output
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let mut devname = None;
+            let mut label = None;
+            let mut fs_type = None;
+            let mut partlabel = None;
+            for line in stanza.lines() {
+                match line.split_once('=') {
+                    Some(("DEVNAME", value)) => devname = Some(unescape_blkid_value(value)),
+                    Some(("LABEL", value)) => label = Some(unescape_blkid_value(value)),
+                    Some(("TYPE", value)) => fs_type = Some(unescape_blkid_value(value)),
+                    Some(("PARTLABEL", value)) => partlabel = Some(unescape_blkid_value(value)),
+                    _ => {}
+                }
+            }
+            Some(BlockDevice {
+                devname: devname?,
+                label,
+                fs_type,
+                partlabel,
+            })
+        })
+        .collect()
+}
+
+// blkid backslash-escapes shell metacharacters (including spaces) in exported values
+fn unescape_blkid_value(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+// udev's symlinks are the cheapest source of labels, but are missing in e.g. initramfs; None
+// means there are none to go by, rather than that no devices are labelled
+pub fn scan_udev(path_root: &Path) -> Option<Vec<BlockDevice>> {
+    if !path_root.join("dev/disk/by-label").is_dir() {
+        return None;
+    }
+    let mut block_devices = scan_by_label(path_root);
+    add_partition_labels(
+        &mut block_devices,
+        read_udev_links(path_root, "by-partlabel"),
+    );
+    Some(block_devices)
+}
+
+// Scanning for environments without blkid
+pub fn scan_without_blkid(path_root: &Path) -> Vec<BlockDevice> {
+    #[cfg(feature = "native-label-probe")]
+    let mut block_devices = scan_native(path_root);
+    #[cfg(not(feature = "native-label-probe"))]
+    let mut block_devices = vec![];
+    // Reading partition tables is slow, so only do it as a last resort
+    let mut partition_labels = read_udev_links(path_root, "by-partlabel");
+    if partition_labels.is_empty() {
+        partition_labels = scan_gpt(path_root);
+    }
+    add_partition_labels(&mut block_devices, partition_labels);
+    block_devices
+}
+
+// Native superblock probing
+#[cfg(feature = "native-label-probe")]
+fn scan_native(path_root: &Path) -> Vec<BlockDevice> {
+    let read_dir = match std::fs::read_dir(path_root.join("sys/class/block")) {
+        Err(_) => return vec![],
+        Ok(read_dir) => read_dir,
+    };
+    let mut names: Vec<String> = read_dir
+        .filter_map(|dir_entry| dir_entry.ok())
+        .filter_map(|dir_entry| dir_entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let mut device = File::open(path_root.join("dev").join(&name)).ok()?;
+            let (fs_type, label) =
+                probe_iso9660(&mut device).or_else(|| probe_vfat(&mut device))?;
+            Some(BlockDevice {
+                devname: format!("/dev/{}", name),
+                label,
+                fs_type: Some(fs_type.to_string()),
+                partlabel: None,
+            })
+        })
+        .collect()
+}
+
+fn read_at(device: &mut File, offset: u64, length: usize) -> Option<Vec<u8>> {
+    let mut buffer = vec![0; length];
+    device.seek(SeekFrom::Start(offset)).ok()?;
+    device.read_exact(&mut buffer).ok()?;
+    Some(buffer)
+}
+
+fn le_u32(bytes: &[u8]) -> usize {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+}
+
+fn padded_label(bytes: &[u8]) -> Option<String> {
+    let label = String::from_utf8_lossy(bytes)
+        .trim_end_matches([' ', '\0'])
+        .to_string();
+    if label.is_empty() || label == "NO NAME" {
+        return None;
+    }
+    Some(label)
+}
+
+// The primary volume descriptor lives in sector 16, with the volume identifier at offset 40
+fn read_primary_volume_descriptor(device: &mut File) -> Option<Vec<u8>> {
+    let descriptor = read_at(device, 16 * 2048, 2048)?;
+    if descriptor[0] != 1 || &descriptor[1..6] != b"CD001" {
+        return None;
+    }
+    Some(descriptor)
+}
+
+#[cfg(feature = "native-label-probe")]
+fn probe_iso9660(device: &mut File) -> Option<(&'static str, Option<String>)> {
+    let descriptor = read_primary_volume_descriptor(device)?;
+    Some(("iso9660", padded_label(&descriptor[40..72])))
+}
+
+// FAT12/16 and FAT32 boot sectors keep the volume label (and a filesystem type string) at
+// different offsets
+#[cfg(feature = "native-label-probe")]
+fn probe_vfat(device: &mut File) -> Option<(&'static str, Option<String>)> {
+    let boot_sector = read_at(device, 0, 512)?;
+    if boot_sector[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    if boot_sector[82..87] == *b"FAT32" {
+        return Some(("vfat", padded_label(&boot_sector[71..82])));
+    }
+    if boot_sector[54..57] == *b"FAT" {
+        return Some(("vfat", padded_label(&boot_sector[43..54])));
+    }
+    None
+}
+
+// CD-ROMs
+pub fn read_iso9660_label(device: &Path) -> Option<String> {
+    let descriptor = read_primary_volume_descriptor(&mut File::open(device).ok()?)?;
+    padded_label(&descriptor[40..72])
+}
+
+// The root directory record sits at offset 156 of the primary volume descriptor; names are
+// returned without their ";<version>" suffix
+pub fn list_iso9660_root(device: &Path) -> Option<Vec<String>> {
+    let mut device = File::open(device).ok()?;
+    let descriptor = read_primary_volume_descriptor(&mut device)?;
+    let root_record = &descriptor[156..190];
+    let extent = le_u32(&root_record[2..6]) as u64;
+    let length = le_u32(&root_record[10..14]).min(64 * 1024);
+    let directory = read_at(&mut device, extent * 2048, length)?;
+    let mut filenames = vec![];
+    let mut offset = 0;
+    while offset < directory.len() {
+        let record_length = directory[offset] as usize;
+        if record_length == 0 {
+            // Records never span sectors, so the rest of this one is padding
+            offset = (offset / 2048 + 1) * 2048;
+            continue;
+        }
+        let name = match directory
+            .get(offset..offset + record_length)
+            .and_then(|record| record.get(33..33 + *record.get(32)? as usize))
+        {
+            None => break,
+            Some(name) => name,
+        };
+        // The first two records are the directory itself ("\0") and its parent ("\x01")
+        if name != [0] && name != [1] {
+            let name = String::from_utf8_lossy(name);
+            let name = name.split(';').next().unwrap_or_default();
+            filenames.push(name.trim_end_matches('.').to_string());
+        }
+        offset += record_length;
+    }
+    Some(filenames)
+}
+
+// Floppies
+// The root directory follows the reserved sectors and FATs; long names are spread across
+// preceding entries in UTF-16LE, so are preferred over the 8.3 name when present
+pub fn list_fat_root(device: &Path) -> Option<Vec<String>> {
+    let mut device = File::open(device).ok()?;
+    let boot_sector = read_at(&mut device, 0, 512)?;
+    if boot_sector[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    let bytes_per_sector = u16::from_le_bytes([boot_sector[11], boot_sector[12]]) as u64;
+    let reserved_sectors = u16::from_le_bytes([boot_sector[14], boot_sector[15]]) as u64;
+    let fat_count = boot_sector[16] as u64;
+    let root_entries = u16::from_le_bytes([boot_sector[17], boot_sector[18]]) as usize;
+    let sectors_per_fat = u16::from_le_bytes([boot_sector[22], boot_sector[23]]) as u64;
+    // FAT32 keeps its root directory in a cluster chain instead, which we don't follow
+    if root_entries == 0 || sectors_per_fat == 0 {
+        return None;
+    }
+    let root_offset = (reserved_sectors + fat_count * sectors_per_fat) * bytes_per_sector;
+    let root = read_at(&mut device, root_offset, root_entries * 32)?;
+
+    let mut filenames = vec![];
+    let mut long_name_parts: Vec<(u8, Vec<u16>)> = vec![];
+    for entry in root.chunks(32) {
+        match (entry[0], entry[11]) {
+            // End of directory
+            (0x00, _) => break,
+            // Deleted
+            (0xE5, _) => long_name_parts.clear(),
+            // Long name part
+            (sequence, 0x0F) => {
+                let chars = [&entry[1..11], &entry[14..26], &entry[28..32]]
+                    .concat()
+                    .chunks(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect();
+                long_name_parts.push((sequence & 0x1F, chars));
+            }
+            // Volume label
+            (_, attributes) if attributes & 0x08 != 0 => long_name_parts.clear(),
+            _ => {
+                let filename = if long_name_parts.is_empty() {
+                    let name = String::from_utf8_lossy(&entry[0..8]).trim_end().to_string();
+                    let extension = String::from_utf8_lossy(&entry[8..11])
+                        .trim_end()
+                        .to_string();
+                    if extension.is_empty() {
+                        name
+                    } else {
+                        format!("{}.{}", name, extension)
+                    }
+                } else {
+                    long_name_parts.sort_by_key(|(sequence, _)| *sequence);
+                    let chars: Vec<u16> = long_name_parts
+                        .drain(..)
+                        .flat_map(|(_, chars)| chars)
+                        .collect();
+                    String::from_utf16_lossy(&chars)
+                };
+                filenames.push(filename);
+            }
+        }
+    }
+    Some(filenames)
+}
+
+// GPT partition labels
+fn add_partition_labels(
+    block_devices: &mut Vec<BlockDevice>,
+    partition_labels: Vec<(String, String)>,
+) {
+    for (devname, partlabel) in partition_labels {
+        match block_devices
+            .iter_mut()
+            .find(|device| device.devname == devname)
+        {
+            Some(device) => device.partlabel = Some(partlabel),
+            None => block_devices.push(BlockDevice {
+                devname,
+                label: None,
+                fs_type: None,
+                partlabel: Some(partlabel),
+            }),
+        }
+    }
+}
+
+// Whole disks which could hold a partition table: not partitions themselves, and not devices
+// (floppies, optical drives, loop and RAM devices) where reading stalls or is pointless
+fn is_partitionable_disk(sys_block_path: &Path, name: &str) -> bool {
+    let read_attribute = |attribute: &str| {
+        std::fs::read_to_string(sys_block_path.join(name).join(attribute))
+            .map(|value| value.trim().to_string())
+            .ok()
+    };
+    // SCSI peripheral type 5 is a CD/DVD drive
+    !["fd", "sr", "loop", "ram", "zram"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        && !sys_block_path.join(name).join("partition").exists()
+        && read_attribute("removable").as_deref() != Some("1")
+        && read_attribute("device/type").as_deref() != Some("5")
+}
+
+// Read the partition table of every whole disk
+fn scan_gpt(path_root: &Path) -> Vec<(String, String)> {
+    let sys_block_path = path_root.join("sys/class/block");
+    let read_dir = match std::fs::read_dir(&sys_block_path) {
+        Err(_) => return vec![],
+        Ok(read_dir) => read_dir,
+    };
+    let mut disk_names: Vec<String> = read_dir
+        .filter_map(|dir_entry| dir_entry.ok())
+        .filter_map(|dir_entry| dir_entry.file_name().into_string().ok())
+        .filter(|name| is_partitionable_disk(&sys_block_path, name))
+        .collect();
+    disk_names.sort();
+    disk_names
+        .into_iter()
+        .flat_map(|disk_name| {
+            read_gpt_partition_names(&path_root.join("dev").join(&disk_name))
+                .into_iter()
+                .map(move |(index, name)| (partition_devname(&disk_name, index), name))
+        })
+        .collect()
+}
+
+// Partitions of disks whose names end in a digit (nvme0n1, mmcblk0) get a "p" separator
+fn partition_devname(disk_name: &str, index: usize) -> String {
+    let separator = if disk_name.ends_with(|c: char| c.is_ascii_digit()) {
+        "p"
+    } else {
+        ""
+    };
+    format!("/dev/{}{}{}", disk_name, separator, index)
+}
+
+// The GPT header is in LBA 1 and points at an array of partition entries, each of which holds
+// its name as UTF-16LE at offset 56; only 512-byte sectors are supported
+fn read_gpt_partition_names(disk: &Path) -> Vec<(usize, String)> {
+    let mut content = vec![];
+    if File::open(disk)
+        .and_then(|file| file.take(1024 * 1024).read_to_end(&mut content))
+        .is_err()
+        || content.len() < 1024
+        || &content[512..520] != b"EFI PART"
+    {
+        return vec![];
+    }
+    let entries_lba = le_u32(&content[512 + 72..]);
+    let entry_count = le_u32(&content[512 + 80..]);
+    let entry_size = le_u32(&content[512 + 84..]);
+    if entry_size < 128 {
+        return vec![];
+    }
+    // These come straight from the disk, so only walk the entries we actually read
+    let entries_start = match entries_lba.checked_mul(512) {
+        Some(entries_start) if entries_start < content.len() => entries_start,
+        _ => return vec![],
+    };
+    let entry_count = entry_count.min((content.len() - entries_start) / entry_size);
+    (0..entry_count)
+        .filter_map(|entry_index| {
+            let start = entry_index
+                .checked_mul(entry_size)
+                .and_then(|offset| offset.checked_add(entries_start))?;
+            let entry = content.get(start..start.checked_add(128)?)?;
+            // An all-zero type GUID marks an unused entry
+            if entry[..16].iter().all(|byte| *byte == 0) {
+                return None;
+            }
+            let name: Vec<u16> = entry[56..128]
+                .chunks(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .take_while(|c| *c != 0)
+                .collect();
+            Some((entry_index + 1, String::from_utf16_lossy(&name)))
+        })
+        .filter(|(_, name)| !name.is_empty())
+        .collect()
+}
+
+// udev
+fn scan_by_label(path_root: &Path) -> Vec<BlockDevice> {
+    read_udev_links(path_root, "by-label")
+        .into_iter()
+        .map(|(devname, label)| BlockDevice {
+            devname,
+            label: Some(label),
+            fs_type: None,
+            partlabel: None,
+        })
+        .collect()
+}
+
+// Map the symlinks in /dev/disk/<kind> to (device, name) pairs
+fn read_udev_links(path_root: &Path, kind: &str) -> Vec<(String, String)> {
+    let read_dir = match std::fs::read_dir(path_root.join("dev/disk").join(kind)) {
+        Err(_) => return vec![],
+        Ok(read_dir) => read_dir,
+    };
+    read_dir
+        .filter_map(|dir_entry| dir_entry.ok())
+        .filter_map(|dir_entry| {
+            let name = dir_entry.file_name().into_string().ok()?;
+            // The symlinks point at "../../<device>"
+            let devname = std::fs::read_link(dir_entry.path())
+                .ok()
+                .and_then(|target| {
+                    target
+                        .file_name()
+                        .map(|name| format!("/dev/{}", name.to_string_lossy()))
+                })
+                .unwrap_or_default();
+            Some((devname, unescape_udev_label(&name)))
+        })
+        .collect()
+}
+
+// udev escapes unsafe characters in /dev/disk/by-label names as \xNN
+fn unescape_udev_label(label: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = label.as_bytes();
+    while !rest.is_empty() {
+        if rest.len() >= 4 && rest.starts_with(b"\\x") {
+            if let Ok(byte) = u8::from_str_radix(&String::from_utf8_lossy(&rest[2..4]), 16) {
+                bytes.push(byte);
+                rest = &rest[4..];
+                continue;
+            }
+        }
+        bytes.push(rest[0]);
+        rest = &rest[1..];
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
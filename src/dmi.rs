@@ -0,0 +1,359 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::path::Path;
+#[cfg(not(target_os = "freebsd"))]
+use std::path::PathBuf;
+
+use std::process::Command;
+
+// A source of DMI values, keyed by their Linux sysfs names (e.g. "product_name")
+pub trait DmiBackend {
+    fn read_field(&self, field_name: &str) -> Option<String>;
+
+    // Whether the platform has DMI at all, as opposed to just lacking particular fields
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+// Linux: /sys/class/dmi/id
+#[cfg(not(target_os = "freebsd"))]
+pub struct Sysfs {
+    dmi_path: PathBuf,
+    dmi_entries_path: PathBuf,
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl Sysfs {
+    pub fn new(path_root: &Path) -> Sysfs {
+        Sysfs {
+            dmi_path: path_root.join("sys/class/dmi/id"),
+            dmi_entries_path: path_root.join("sys/firmware/dmi/entries"),
+        }
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl DmiBackend for Sysfs {
+    fn read_field(&self, field_name: &str) -> Option<String> {
+        let value = std::fs::read_to_string(self.dmi_path.join(field_name))
+            .map(|s| s.trim().to_string())
+            .ok();
+        if value.is_none() && field_name == "oem" {
+            return self.read_oem_entries();
+        }
+        value
+    }
+
+    fn is_available(&self) -> bool {
+        self.dmi_path.exists()
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl Sysfs {
+    // Most kernels only expose the OEM strings (SMBIOS type 11) as raw table entries, so parse
+    // those; multiple strings are newline-separated, as dmidecode would print them
+    fn read_oem_entries(&self) -> Option<String> {
+        let mut strings = vec![];
+        let mut entry_index = 0;
+        while let Ok(raw) = std::fs::read(
+            self.dmi_entries_path
+                .join(format!("11-{}", entry_index))
+                .join("raw"),
+        ) {
+            // A 4-byte header (type, length, handle) and a count precede the string table
+            let formatted_length = *raw.get(1)? as usize;
+            let string_count = *raw.get(4)? as usize;
+            strings.extend(
+                raw.get(formatted_length..)?
+                    .split(|byte| *byte == 0)
+                    .take(string_count)
+                    .map(|value| String::from_utf8_lossy(value).trim().to_string()),
+            );
+            entry_index += 1;
+        }
+        if strings.is_empty() {
+            return None;
+        }
+        Some(strings.join("\n"))
+    }
+}
+
+// dmidecode, for when sysfs is missing or (as with product_serial) only readable by root
+#[cfg(not(target_os = "freebsd"))]
+pub struct Dmidecode {
+    dmidecode: PathBuf,
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl Dmidecode {
+    pub fn new(dmidecode: &Path) -> Dmidecode {
+        Dmidecode {
+            dmidecode: dmidecode.to_path_buf(),
+        }
+    }
+
+    fn keyword(field_name: &str) -> Option<&'static str> {
+        match field_name {
+            "bios_vendor" => Some("bios-vendor"),
+            "board_asset_tag" => Some("baseboard-asset-tag"),
+            "board_name" => Some("baseboard-product-name"),
+            "board_serial" => Some("baseboard-serial-number"),
+            "board_vendor" => Some("baseboard-manufacturer"),
+            "chassis_asset_tag" => Some("chassis-asset-tag"),
+            "chassis_vendor" => Some("chassis-manufacturer"),
+            "product_name" => Some("system-product-name"),
+            "product_serial" => Some("system-serial-number"),
+            "product_uuid" => Some("system-uuid"),
+            "sys_vendor" => Some("system-manufacturer"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl DmiBackend for Dmidecode {
+    fn read_field(&self, field_name: &str) -> Option<String> {
+        if field_name == "oem" {
+            return self.read_oem_strings();
+        }
+        let output = Command::new(&self.dmidecode)
+            .args(["-s", Dmidecode::keyword(field_name)?])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        // Diagnostics (e.g. "# No SMBIOS nor DMI entry point found") are emitted as comments
+        String::from_utf8(output.stdout)
+            .ok()?
+            .lines()
+            .find(|line| !line.starts_with('#'))
+            .map(|value| value.trim().to_string())
+    }
+
+    // dmidecode runs happily without any tables to decode, so see if it finds anything
+    fn is_available(&self) -> bool {
+        self.read_field("product_uuid").is_some()
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl Dmidecode {
+    // OEM strings have no -s keyword, so pick them out of the "String <n>: <value>" lines
+    fn read_oem_strings(&self) -> Option<String> {
+        let output = Command::new(&self.dmidecode)
+            .args(["-q", "-t", "11"])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let strings: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("String "))
+            .filter_map(|line| line.split_once(": "))
+            .map(|(_, value)| value.trim().to_string())
+            .collect();
+        if strings.is_empty() {
+            return None;
+        }
+        Some(strings.join("\n"))
+    }
+}
+
+// ARM and other device-tree platforms, which often lack some or all of the DMI tables
+#[cfg(not(target_os = "freebsd"))]
+pub struct DeviceTree {
+    device_tree_path: PathBuf,
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl DeviceTree {
+    pub fn new(path_root: &Path) -> DeviceTree {
+        DeviceTree {
+            device_tree_path: path_root.join("proc/device-tree"),
+        }
+    }
+
+    // Device tree properties are NUL-terminated (and string lists NUL-separated)
+    fn read_property(&self, property: &str) -> Option<Vec<String>> {
+        let content = std::fs::read(self.device_tree_path.join(property)).ok()?;
+        Some(
+            content
+                .split(|byte| *byte == 0)
+                .map(|value| String::from_utf8_lossy(value).trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl DmiBackend for DeviceTree {
+    fn read_field(&self, field_name: &str) -> Option<String> {
+        match field_name {
+            "product_name" => self.read_property("model")?.into_iter().next(),
+            "product_serial" => self
+                .read_property("serial-number")
+                // POWER systems have no serial-number, but do identify the machine
+                .or_else(|| self.read_property("system-id"))?
+                .into_iter()
+                .next(),
+            "product_uuid" => self.read_property("ibm,partition-uuid")?.into_iter().next(),
+            // The most specific "compatible" entry is "<vendor>,<model>"
+            "sys_vendor" => self
+                .read_property("compatible")?
+                .into_iter()
+                .next()
+                .and_then(|compatible| {
+                    compatible
+                        .split_once(',')
+                        .map(|(vendor, _)| vendor.to_string())
+                }),
+            _ => None,
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.device_tree_path.exists()
+    }
+}
+
+// s390x, which has no DMI; /proc/sysinfo describes the machine and any hypervisor layers
+#[cfg(not(target_os = "freebsd"))]
+pub struct Sysinfo {
+    sysinfo_path: PathBuf,
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl Sysinfo {
+    pub fn new(path_root: &Path) -> Sysinfo {
+        Sysinfo {
+            sysinfo_path: path_root.join("proc/sysinfo"),
+        }
+    }
+
+    // Lines are "<key>: <value>", with the key padded by spaces
+    fn read_key(&self, key: &str) -> Option<String> {
+        std::fs::read_to_string(&self.sysinfo_path)
+            .ok()?
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(line_key, _)| line_key.trim() == key)
+            .map(|(_, value)| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl DmiBackend for Sysinfo {
+    fn read_field(&self, field_name: &str) -> Option<String> {
+        match field_name {
+            // Guests see the hypervisor ("KVM/Linux", "z/VM ...") as the product; LPARs only
+            // have the machine type
+            "product_name" => self
+                .read_key("VM00 Control Program")
+                .or_else(|| self.read_key("Type")),
+            "product_serial" => self.read_key("Sequence Code"),
+            "sys_vendor" => self.read_key("Manufacturer"),
+            _ => None,
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.sysinfo_path.exists()
+    }
+}
+
+// Try each backend in turn, using the first value found
+#[cfg(not(target_os = "freebsd"))]
+pub struct Fallback {
+    backends: Vec<Box<dyn DmiBackend>>,
+}
+
+#[cfg(not(target_os = "freebsd"))]
+impl DmiBackend for Fallback {
+    fn read_field(&self, field_name: &str) -> Option<String> {
+        self.backends
+            .iter()
+            .find_map(|backend| backend.read_field(field_name))
+    }
+
+    fn is_available(&self) -> bool {
+        self.backends.iter().any(|backend| backend.is_available())
+    }
+}
+
+// DI_DMI_<FIELD> environment variables (e.g. DI_DMI_PRODUCT_NAME), which take precedence over
+// what the platform reports; an empty value means the field is absent
+pub fn env_override(field_name: &str) -> Option<Option<String>> {
+    std::env::var(format!("DI_DMI_{}", field_name.to_ascii_uppercase()))
+        .ok()
+        .map(|value| Some(value).filter(|value| !value.is_empty()))
+}
+
+// Any override stands in for the platform's DMI data
+pub fn has_env_overrides() -> bool {
+    std::env::vars().any(|(name, _)| name.starts_with("DI_DMI_"))
+}
+
+// FreeBSD: the kernel environment populated from SMBIOS by the loader
+#[cfg(target_os = "freebsd")]
+pub struct Kenv;
+
+#[cfg(target_os = "freebsd")]
+impl Kenv {
+    fn kenv_name(field_name: &str) -> Option<&'static str> {
+        match field_name {
+            "bios_vendor" => Some("smbios.bios.vendor"),
+            "board_asset_tag" => Some("smbios.planar.tag"),
+            "board_name" => Some("smbios.planar.product"),
+            "board_serial" => Some("smbios.planar.serial"),
+            "board_vendor" => Some("smbios.planar.maker"),
+            "chassis_asset_tag" => Some("smbios.chassis.tag"),
+            "chassis_vendor" => Some("smbios.chassis.maker"),
+            "product_name" => Some("smbios.system.product"),
+            "product_serial" => Some("smbios.system.serial"),
+            "product_uuid" => Some("smbios.system.uuid"),
+            "sys_vendor" => Some("smbios.system.maker"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+impl DmiBackend for Kenv {
+    fn read_field(&self, field_name: &str) -> Option<String> {
+        let output = Command::new("kenv")
+            .args(["-q", Kenv::kenv_name(field_name)?])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout)
+            .ok()
+            .map(|value| value.trim().to_string())
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+pub fn default_backend(_path_root: &Path, _dmidecode: &Path) -> Box<dyn DmiBackend> {
+    Box::new(Kenv)
+}
+
+#[cfg(not(target_os = "freebsd"))]
+pub fn default_backend(path_root: &Path, dmidecode: &Path) -> Box<dyn DmiBackend> {
+    Box::new(Fallback {
+        backends: vec![
+            Box::new(Sysfs::new(path_root)),
+            Box::new(Dmidecode::new(dmidecode)),
+            Box::new(DeviceTree::new(path_root)),
+            Box::new(Sysinfo::new(path_root)),
+        ],
+    })
+}
@@ -0,0 +1,27 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// /etc/cloud/ds-identify.cfg holds one "key: value" (or "key=value") setting per line, with '#'
+// starting a comment; later lines win. Besides rs-identify's own settings, it takes upstream's
+// "policy" and "datasource", and "disabled" as an alternative to etc/cloud/cloud-init.disabled
+pub fn read(path_root: &Path) -> BTreeMap<String, String> {
+    let content = match std::fs::read_to_string(path_root.join("etc/cloud/ds-identify.cfg")) {
+        Err(_) => return BTreeMap::new(),
+        Ok(content) => content,
+    };
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter_map(|line| {
+            // Values can themselves contain '=' (e.g. "policy: search,found=all"), so split at
+            // whichever separator comes first
+            let separator = line.find([':', '='])?;
+            let (key, value) = (&line[..separator], &line[separator + 1..]);
+            Some((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
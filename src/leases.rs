@@ -0,0 +1,72 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::path::Path;
+
+use crate::sorted_dir_entries;
+
+type LeaseParser = fn(&[u8]) -> Option<String>;
+
+// The DHCP server identifier from whichever DHCP client's leases are present
+pub fn find_dhcp_server(path_root: &Path) -> Option<String> {
+    let lease_dirs: &[(&str, LeaseParser)] = &[
+        ("run/systemd/netif/leases", parse_networkd_lease),
+        ("var/lib/dhclient", parse_dhclient_leases),
+        ("var/lib/dhcp", parse_dhclient_leases),
+        ("var/lib/dhcpcd", parse_dhcpcd_lease),
+    ];
+    lease_dirs.iter().find_map(|(lease_dir, parse)| {
+        sorted_dir_entries(&path_root.join(lease_dir))
+            .iter()
+            .filter_map(|lease_path| std::fs::read(lease_path).ok())
+            .find_map(|content| parse(&content))
+    })
+}
+
+// systemd-networkd leases are KEY=value files named for the interface index
+fn parse_networkd_lease(content: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .find_map(|line| line.strip_prefix("SERVER_ADDRESS="))
+        .map(|value| value.trim().to_string())
+}
+
+// dhclient leases contain "option dhcp-server-identifier <addr>;" lines; the last one in a file
+// is the most recent lease
+fn parse_dhclient_leases(content: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("option dhcp-server-identifier "))
+        .next_back()
+        .map(|value| value.trim_end_matches(';').trim().to_string())
+}
+
+// dhcpcd stores the raw DHCP message: a fixed 236-byte BOOTP header and the magic cookie,
+// followed by the options as (code, length, value)
+fn parse_dhcpcd_lease(content: &[u8]) -> Option<String> {
+    if content.get(236..240)? != [99, 130, 83, 99] {
+        return None;
+    }
+    let mut options = content.get(240..)?;
+    loop {
+        match options.first()? {
+            // Pad
+            0 => options = &options[1..],
+            // End
+            255 => return None,
+            code => {
+                let length = *options.get(1)? as usize;
+                let value = options.get(2..2 + length)?;
+                // Server identifier
+                if *code == 54 && length == 4 {
+                    return Some(format!(
+                        "{}.{}.{}.{}",
+                        value[0], value[1], value[2], value[3]
+                    ));
+                }
+                options = &options[2 + length..];
+            }
+        }
+    }
+}
@@ -2,84 +2,407 @@
 //
 // Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
 
+mod cmdline;
+mod devices;
+mod dmi;
+mod ds_identify_cfg;
+mod leases;
+mod mount;
+mod policy;
+mod probe;
+mod uname;
+mod virt;
+
 use std::collections::BTreeMap;
 use std::fs::{create_dir_all, File};
-use std::io::Write;
-use std::path::PathBuf;
-
-#[cfg(target_os = "freebsd")]
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant};
+
+// The link-local address most metadata services are reachable at
+const METADATA_SERVICE_HOST: &str = "169.254.169.254";
+
+// Exit statuses
+const EXIT_FOUND: i32 = 0;
+const EXIT_DISABLED: i32 = 1;
+const EXIT_EMPTY_LIST: i32 = 3;
+const EXIT_USAGE: i32 = 64;
+
+// How often to rescan while waiting for seed devices to appear
+const SEED_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Datasources to search when no datasource_list is configured
+const DEFAULT_DATASOURCE_LIST: &[&str] = &[
+    "Akamai",
+    "AliYun",
+    "AltCloud",
+    "Azure",
+    "Bigstep",
+    "CloudSigma",
+    "CloudStack",
+    "ConfigDrive",
+    "DigitalOcean",
+    "Ec2",
+    "Exoscale",
+    "GCE",
+    "Gandi",
+    "Hetzner",
+    "LXD",
+    "MAAS",
+    "NWCS",
+    "NoCloud",
+    "OVF",
+    "OpenNebula",
+    "OpenStack",
+    "Oracle",
+    "RbxCloud",
+    "Scaleway",
+    "SmartOS",
+    "TencentCloud",
+    "UpCloud",
+    "VMware",
+    "Vultr",
+];
+
+// Chassis asset tags which identify the datasource a platform uses; a trailing '*' matches any
+// suffix
+const CHASSIS_ASSET_TAG_DATASOURCES: &[(&str, &str)] = &[
+    ("HUAWEICLOUD*", "OpenStack"),
+    ("OpenStack Compute", "OpenStack"),
+    ("OpenStack Nova", "OpenStack"),
+    ("OpenTelekomCloud", "OpenStack"),
+    ("SAP CCloud VM", "OpenStack"),
+];
+
+// Other names datasources go by (lowercase), and the datasource they refer to
+const DATASOURCE_ALIASES: &[(&str, &str)] = &[
+    ("ali-yun", "AliYun"),
+    ("azurenet", "Azure"),
+    ("linode", "Akamai"),
+    ("nocloud-net", "NoCloud"),
+];
+
+// The file AltCloud's user data is delivered in
+const ALTCLOUD_USER_DATA: &str = "deltacloud-user-data.txt";
+
+// Filesystem labels used for OVF transport media
+const OVF_LABELS: &[&str] = &[
+    "OVF-ENV",
+    "OVF-TRANSPORT",
+    "ovf-transport",
+    "OVFENV",
+    "ovfenv",
+    "OVF ENV",
+    "ovf env",
+];
 
 struct RsIdentify {
     // Paths
     path_root: PathBuf,
     cfg_out: PathBuf,
 
+    // Settings
+    vmware_skip_rpc: bool,
+    systemd_detect_virt: PathBuf,
+    blkid: PathBuf,
+    probe_policy: probe::ProbePolicy,
+    ec2_require_imds: bool,
+    wait_for_seed: Option<Duration>,
+    altcloud_probe_payload: bool,
+    ds_identify_cfg: BTreeMap<String, String>,
+
+    container: Option<Option<String>>,
+    virt: Option<virt::Virt>,
+    uname: Option<uname::Uname>,
+    dmi_backend: Box<dyn dmi::DmiBackend>,
     dmi_values: BTreeMap<String, Option<String>>,
+    dmi_absent: Option<bool>,
+    kernel_cmdline: Option<String>,
+    block_devices: Option<Vec<devices::BlockDevice>>,
+    #[cfg(feature = "network-probe")]
+    probe_time_spent: Duration,
+
+    // Output
+    policy: policy::Policy,
+    check_results: Vec<(String, policy::CheckResult)>,
+    datasource_config: serde_yaml::Mapping,
 }
 
 impl RsIdentify {
     // Setup
-    fn new(path_root: PathBuf) -> RsIdentify {
+    fn new(
+        path_root: PathBuf,
+        vmware_skip_rpc: bool,
+        systemd_detect_virt: Option<PathBuf>,
+        blkid: Option<PathBuf>,
+        dmidecode: Option<PathBuf>,
+        probe_policy: probe::ProbePolicy,
+        ec2_require_imds: bool,
+    ) -> RsIdentify {
         let mut cfg_out = path_root.clone();
         cfg_out.push("run/cloud-init/cloud.cfg");
+        let systemd_detect_virt = systemd_detect_virt.unwrap_or_else(|| {
+            let mut systemd_detect_virt = path_root.clone();
+            systemd_detect_virt.push("usr/bin/systemd-detect-virt");
+            systemd_detect_virt
+        });
+        let blkid = blkid.unwrap_or_else(|| {
+            let mut blkid = path_root.clone();
+            blkid.push("sbin/blkid");
+            blkid
+        });
+        let dmidecode = dmidecode.unwrap_or_else(|| {
+            let mut dmidecode = path_root.clone();
+            dmidecode.push("usr/sbin/dmidecode");
+            dmidecode
+        });
 
         // Emit our paths/settings
         println!("PATH_ROOT: {}", path_root.display());
         println!("CFG_OUT: {}", cfg_out.display());
+        println!("VMWARE_SKIP_RPC: {}", vmware_skip_rpc);
+        println!("SYSTEMD_DETECT_VIRT: {}", systemd_detect_virt.display());
+        println!("BLKID: {}", blkid.display());
+        println!("DMIDECODE: {}", dmidecode.display());
+        println!("NETWORK_PROBE: {}", probe_policy);
+        println!("EC2_REQUIRE_IMDS: {}", ec2_require_imds);
+
+        let dmi_backend = dmi::default_backend(&path_root, &dmidecode);
 
         RsIdentify {
             path_root,
             cfg_out,
+            vmware_skip_rpc,
+            systemd_detect_virt,
+            blkid,
+            probe_policy,
+            ec2_require_imds,
+            wait_for_seed: None,
+            altcloud_probe_payload: false,
+            ds_identify_cfg: BTreeMap::new(),
+            container: None,
+            virt: None,
+            uname: None,
+            dmi_backend,
             dmi_values: BTreeMap::new(),
+            dmi_absent: None,
+            kernel_cmdline: None,
+            block_devices: None,
+            #[cfg(feature = "network-probe")]
+            probe_time_spent: Duration::ZERO,
+            policy: policy::Policy::default(),
+            check_results: vec![],
+            datasource_config: serde_yaml::Mapping::new(),
         }
     }
 
-    fn from_env() -> RsIdentify {
+    fn from_env(wait_for_seed: Option<Duration>) -> RsIdentify {
         let path_root = match std::env::var("PATH_ROOT") {
             Ok(val) => PathBuf::from(&val),
             Err(_) => PathBuf::from("/"),
         };
-        RsIdentify::new(path_root)
+        let systemd_detect_virt = std::env::var("DI_SYSTEMD_DETECT_VIRT")
+            .map(PathBuf::from)
+            .ok();
+        let blkid = std::env::var("DI_BLKID").map(PathBuf::from).ok();
+        let dmidecode = std::env::var("DI_DMIDECODE").map(PathBuf::from).ok();
+        let ds_identify_cfg = ds_identify_cfg::read(&path_root);
+        // The environment overrides ds-identify.cfg
+        let setting = |env_name: &str, cfg_key: &str| {
+            std::env::var(env_name)
+                .ok()
+                .or_else(|| ds_identify_cfg.get(cfg_key).cloned())
+        };
+        // Minimal systems may not want to spawn VMware tools just to confirm the platform
+        let vmware_skip_rpc = setting("DI_VMWARE_SKIP_RPC", "vmware_skip_rpc")
+            .map(|val| val == "1" || val == "true")
+            .unwrap_or(false);
+        let parse_secs = |value: String| {
+            value
+                .parse()
+                .ok()
+                .filter(|secs: &f64| secs.is_finite() && *secs >= 0.0)
+                .map(Duration::from_secs_f64)
+        };
+        // Querying metadata services can stall boot, so it has to be asked for
+        let default_probe_policy = probe::ProbePolicy::default();
+        let probe_policy = probe::ProbePolicy {
+            enabled: setting("DI_NETWORK_PROBE", "network_probe")
+                .map(|val| ["1", "true", "enabled"].contains(&val.as_str()))
+                .unwrap_or(default_probe_policy.enabled),
+            timeout: setting("DI_NETWORK_PROBE_TIMEOUT", "network_probe_timeout")
+                .and_then(parse_secs)
+                .filter(|timeout| !timeout.is_zero())
+                .unwrap_or(default_probe_policy.timeout),
+            budget: setting("DI_NETWORK_PROBE_BUDGET", "network_probe_budget")
+                .and_then(parse_secs)
+                .unwrap_or(default_probe_policy.budget),
+            retries: setting("DI_NETWORK_PROBE_RETRIES", "network_probe_retries")
+                .and_then(|val| val.parse().ok())
+                .unwrap_or(default_probe_policy.retries),
+        };
+        // Some clouds fake EC2 serials, so allow requiring the instance metadata service to
+        // confirm AWS when the hardware isn't branded as such
+        let ec2_require_imds = setting("DI_EC2_REQUIRE_IMDS", "ec2_require_imds")
+            .map(|val| val == "1" || val == "true")
+            .unwrap_or(false);
+        // Reading an empty floppy drive stalls boot, so AltCloud's payload has to be asked for
+        let altcloud_probe_payload = setting("DI_ALTCLOUD_PROBE_PAYLOAD", "altcloud_probe_payload")
+            .map(|val| val == "1" || val == "true")
+            .unwrap_or(false);
+        let mut rs_identify = RsIdentify::new(
+            path_root,
+            vmware_skip_rpc,
+            systemd_detect_virt,
+            blkid,
+            dmidecode,
+            probe_policy,
+            ec2_require_imds,
+        );
+        if let Some(wait_for_seed) = wait_for_seed {
+            println!("WAIT_FOR_SEED: {:?}", wait_for_seed);
+        }
+        rs_identify.wait_for_seed = wait_for_seed;
+        println!("ALTCLOUD_PROBE_PAYLOAD: {}", altcloud_probe_payload);
+        rs_identify.altcloud_probe_payload = altcloud_probe_payload;
+        rs_identify.ds_identify_cfg = ds_identify_cfg;
+        rs_identify
+    }
+
+    // Container detection caching
+    fn get_container(&mut self) -> &Option<String> {
+        if self.container.is_none() {
+            // Prefer reading init's environment to spawning a subprocess
+            let container = virt::detect_container_environ(&self.path_root)
+                .or_else(|| virt::detect_container_run_file(&self.path_root))
+                .or_else(|| virt::detect_container_markers(&self.path_root))
+                .or_else(|| {
+                    // WSL has no other marker, but brands its kernel
+                    let kernel_release = self.get_uname().kernel_release.to_ascii_lowercase();
+                    Some("wsl".to_string()).filter(|_| kernel_release.contains("microsoft"))
+                })
+                .or_else(|| virt::detect_container_systemd(&self.systemd_detect_virt));
+            if let Some(container) = &container {
+                println!("Running in a container: {}", container);
+            }
+            self.container = Some(container);
+        }
+        self.container.as_ref().unwrap()
+    }
+
+    fn is_container(&mut self) -> bool {
+        self.get_container().is_some()
+    }
+
+    // Kernel identification caching
+    fn get_uname(&mut self) -> &uname::Uname {
+        if self.uname.is_none() {
+            let uname = uname::Uname::read(&self.path_root);
+            println!(
+                "uname: {} {} {} {}",
+                uname.kernel_name, uname.kernel_release, uname.kernel_version, uname.machine
+            );
+            self.uname = Some(uname);
+        }
+        self.uname.as_ref().unwrap()
+    }
+
+    // Virtualization detection caching
+    fn get_virt(&mut self) -> &virt::Virt {
+        if self.virt.is_none() {
+            let detected = match self.get_container().clone() {
+                Some(container) => virt::Virt::Container(container),
+                None => {
+                    // The same DMI fields systemd-detect-virt checks, plus the chassis vendor
+                    let dmi_values: Vec<String> = vec![
+                        self.dmi_sys_vendor().clone(),
+                        self.dmi_product_name().clone(),
+                        self.dmi_board_vendor().clone(),
+                        self.dmi_bios_vendor().clone(),
+                        self.dmi_chassis_vendor().clone(),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                    virt::detect_hypervisor(&self.path_root, &dmi_values)
+                }
+            };
+            println!("Virtualization: {}", detected);
+            self.virt = Some(detected);
+        }
+        self.virt.as_ref().unwrap()
     }
 
     // DMI caching
-    #[cfg(target_os = "linux")]
     fn get_dmi_field(&mut self, field_name: &str) -> &Option<String> {
         if !self.dmi_values.contains_key(field_name) {
-            let mut path = self.path_root.clone();
-            path.push("sys/class/dmi/id");
-            path.push(field_name);
-
-            let value = std::fs::read_to_string(&path)
-                .map(|s| s.trim().to_string())
-                .ok();
+            // Containers can see the host's DMI data, which says nothing about the container
+            // itself; overrides in the environment win regardless
+            let value = match dmi::env_override(field_name) {
+                Some(value) => value,
+                None if self.is_container() => None,
+                None => self.dmi_backend.read_field(field_name),
+            };
             self.dmi_values.insert(field_name.to_string(), value);
         }
         self.dmi_values.get(field_name).unwrap()
     }
 
-    #[cfg(target_os = "freebsd")]
-    fn get_dmi_field(&mut self, field_name: &str) -> &Option<String> {
-        let field_name_cmd = field_name.replace('_', "-");
-        if !self.dmi_values.contains_key(field_name) {
-            let output = Command::new("dmidecode")
-                .args(&["--string", &field_name_cmd])
-                .output()
-                .expect("failed to execute dmidecode");
-            String::from_utf8(output.stdout)
-                .ok()
-                .map(|v| self.dmi_values.insert(field_name.to_string(), Some(v)));
+    // Xen PV guests (and some containers) have no DMI at all, which calls for different
+    // heuristics than a platform which simply leaves some fields unset
+    fn is_dmi_absent(&mut self) -> bool {
+        if self.dmi_absent.is_none() {
+            let dmi_absent = !self.is_container()
+                && !dmi::has_env_overrides()
+                && !self.dmi_backend.is_available();
+            if dmi_absent {
+                println!("DMI: absent");
+            }
+            self.dmi_absent = Some(dmi_absent);
         }
-        self.dmi_values.get(field_name).unwrap()
+        self.dmi_absent.unwrap()
+    }
+
+    fn dmi_bios_vendor(&mut self) -> &Option<String> {
+        self.get_dmi_field("bios_vendor")
+    }
+
+    fn dmi_board_asset_tag(&mut self) -> &Option<String> {
+        self.get_dmi_field("board_asset_tag")
+    }
+
+    fn dmi_board_name(&mut self) -> &Option<String> {
+        self.get_dmi_field("board_name")
+    }
+
+    fn dmi_board_serial(&mut self) -> &Option<String> {
+        self.get_dmi_field("board_serial")
+    }
+
+    fn dmi_board_vendor(&mut self) -> &Option<String> {
+        self.get_dmi_field("board_vendor")
     }
 
     fn dmi_chassis_asset_tag(&mut self) -> &Option<String> {
         self.get_dmi_field("chassis_asset_tag")
     }
 
+    fn dmi_chassis_vendor(&mut self) -> &Option<String> {
+        self.get_dmi_field("chassis_vendor")
+    }
+
+    // OEM strings are newline-separated
+    fn dmi_oem_strings(&mut self) -> Vec<String> {
+        self.get_dmi_field("oem")
+            .as_deref()
+            .unwrap_or("")
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
     fn dmi_product_name(&mut self) -> &Option<String> {
-        // TODO: container check
         self.get_dmi_field("product_name")
     }
 
@@ -91,219 +414,1931 @@ impl RsIdentify {
         self.get_dmi_field("product_uuid")
     }
 
+    fn dmi_sys_vendor(&mut self) -> &Option<String> {
+        self.get_dmi_field("sys_vendor")
+    }
+
+    // Kernel command line caching
+    fn get_kernel_cmdline(&mut self) -> &str {
+        if self.kernel_cmdline.is_none() {
+            let mut path = self.path_root.clone();
+            path.push("proc/cmdline");
+
+            let value = std::fs::read_to_string(&path)
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default();
+            self.kernel_cmdline = Some(value);
+        }
+        self.kernel_cmdline.as_ref().unwrap()
+    }
+
+    fn kernel_cmdline_has_token(&mut self, token: &str) -> bool {
+        self.get_kernel_cmdline()
+            .split_whitespace()
+            .any(|cmdline_token| cmdline_token == token)
+    }
+
+    // Block device caching
+    fn get_block_devices(&mut self) -> &Vec<devices::BlockDevice> {
+        if self.block_devices.is_none() {
+            // Only run blkid (or probe devices ourselves) when udev hasn't done the work for us
+            let block_devices = devices::scan_udev(&self.path_root)
+                .or_else(|| devices::scan_blkid(&self.blkid))
+                .unwrap_or_else(|| {
+                    println!("blkid failed; scanning devices without it");
+                    devices::scan_without_blkid(&self.path_root)
+                });
+            for device in &block_devices {
+                println!(
+                    "Block device: {} LABEL={} TYPE={} PARTLABEL={}",
+                    device.devname,
+                    device.label.as_deref().unwrap_or(""),
+                    device.fs_type.as_deref().unwrap_or(""),
+                    device.partlabel.as_deref().unwrap_or("")
+                );
+            }
+            self.block_devices = Some(block_devices);
+        }
+        self.block_devices.as_ref().unwrap()
+    }
+
+    fn get_fs_labels(&mut self) -> impl Iterator<Item = &String> {
+        self.get_block_devices()
+            .iter()
+            .filter_map(|device| device.label.as_ref())
+    }
+
+    fn has_fs_with_label(&mut self, labels: &[&str]) -> bool {
+        self.get_fs_labels()
+            .any(|fs_label| labels.contains(&fs_label.as_str()))
+    }
+
+    fn has_partition_with_label(&mut self, partlabels: &[&str]) -> bool {
+        self.get_block_devices()
+            .iter()
+            .filter_map(|device| device.partlabel.as_ref())
+            .any(|partlabel| partlabels.contains(&partlabel.as_str()))
+    }
+
+    fn has_fs_with_label_ignore_case(&mut self, label: &str) -> bool {
+        self.get_fs_labels()
+            .any(|fs_label| fs_label.eq_ignore_ascii_case(label))
+    }
+
+    fn has_fs_with_label_prefix(&mut self, prefix: &str) -> bool {
+        self.get_fs_labels()
+            .any(|fs_label| fs_label.starts_with(prefix))
+    }
+
+    // Network probing; None means probing is unavailable (disabled, not built in or out of
+    // budget), so checks should fall back to their local heuristics
+    #[cfg(feature = "network-probe")]
+    fn probe_metadata_service(
+        &mut self,
+        host: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Option<bool> {
+        if !self.probe_available() {
+            return None;
+        }
+        let found = self
+            .metadata_request("GET", host, path, headers)
+            .map(|response| response.status == 200)
+            .unwrap_or(false);
+        println!("Probed http://{}{}: {}", host, path, found);
+        Some(found)
+    }
+
+    #[cfg(not(feature = "network-probe"))]
+    fn probe_metadata_service(
+        &mut self,
+        _host: &str,
+        _path: &str,
+        _headers: &[(&str, &str)],
+    ) -> Option<bool> {
+        if self.probe_policy.enabled {
+            println!("Network probing requested, but not built in");
+        }
+        None
+    }
+
+    #[cfg(feature = "network-probe")]
+    fn probe_available(&self) -> bool {
+        if !self.probe_policy.enabled {
+            return false;
+        }
+        if self.probe_time_spent >= self.probe_policy.budget {
+            println!("Network probe budget exhausted");
+            return false;
+        }
+        true
+    }
+
+    // Requests are retried only if nothing answers, and never run past the overall budget
+    #[cfg(feature = "network-probe")]
+    fn metadata_request(
+        &mut self,
+        method: &str,
+        host: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Option<probe::Response> {
+        for _ in 0..=self.probe_policy.retries {
+            let remaining = self
+                .probe_policy
+                .budget
+                .checked_sub(self.probe_time_spent)?;
+            if remaining.is_zero() {
+                return None;
+            }
+            let started = Instant::now();
+            let response = probe::http_request(
+                method,
+                host,
+                path,
+                headers,
+                self.probe_policy.timeout.min(remaining),
+            );
+            self.probe_time_spent += started.elapsed();
+            if response.is_some() {
+                return response;
+            }
+        }
+        None
+    }
+
+    // IMDSv2 hands out a session token which must accompany requests; fall back to IMDSv1 if
+    // one can't be had
+    #[cfg(feature = "network-probe")]
+    fn probe_ec2_imds(&mut self) -> Option<bool> {
+        if !self.probe_available() {
+            return None;
+        }
+        let token = self
+            .metadata_request(
+                "PUT",
+                METADATA_SERVICE_HOST,
+                "/latest/api/token",
+                &[
+                    ("X-aws-ec2-metadata-token-ttl-seconds", "60"),
+                    ("Content-Length", "0"),
+                ],
+            )
+            .filter(|response| response.status == 200)
+            .map(|response| response.body.trim().to_string());
+        let headers: Vec<(&str, &str)> = token
+            .iter()
+            .map(|token| ("X-aws-ec2-metadata-token", token.as_str()))
+            .collect();
+        self.probe_metadata_service(
+            METADATA_SERVICE_HOST,
+            "/latest/meta-data/instance-id",
+            &headers,
+        )
+    }
+
+    #[cfg(not(feature = "network-probe"))]
+    fn probe_ec2_imds(&mut self) -> Option<bool> {
+        self.probe_metadata_service(METADATA_SERVICE_HOST, "/latest/meta-data/instance-id", &[])
+    }
+
     // Helpers
-    fn seed_path_exists(&self, prefix: Option<&str>, seed_type: &str, filename: &str) -> bool {
+    fn disabled_marker_exists(&self) -> bool {
+        let mut marker_path = self.path_root.clone();
+        marker_path.push("etc/cloud/cloud-init.disabled");
+        marker_path.exists()
+    }
+
+    fn is_azure_chassis(&mut self) -> bool {
+        self.dmi_chassis_asset_tag() == &Some("7783-7084-3265-9085-8269-3286-77".to_string())
+    }
+
+    fn is_powervm_lpar(&self) -> bool {
+        let mut partition_name_path = self.path_root.clone();
+        partition_name_path.push("proc/device-tree/ibm,partition-name");
+        partition_name_path.exists()
+    }
+
+    // Platforms can pass "cloud-init:ds=<datasource>" hints in SMBIOS OEM strings, which are
+    // honoured as if they were on the kernel command line
+    fn find_oem_datasource_arg(&mut self) -> Option<cmdline::DatasourceArg> {
+        self.dmi_oem_strings()
+            .iter()
+            .filter_map(|oem_string| oem_string.strip_prefix("cloud-init:"))
+            .filter_map(cmdline::find_datasource_arg)
+            .next_back()
+    }
+
+    fn is_xen(&self) -> bool {
+        virt::detect_xen(&self.path_root)
+    }
+
+    // IBM Cloud boots its provisioning image with the provisioning config present; the
+    // installer's log is only from this boot (i.e. newer than init) while that's under way
+    fn is_ibm_provisioning(&self) -> bool {
+        let mut provisioning_config_path = self.path_root.clone();
+        provisioning_config_path.push("root/provisioningConfiguration.cfg");
+        if !provisioning_config_path.is_file() {
+            return false;
+        }
+        let mut log_path = self.path_root.clone();
+        log_path.push("root/swinstall.log");
+        let mut init_environ_path = self.path_root.clone();
+        init_environ_path.push("proc/1/environ");
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|meta| meta.modified());
+        match (modified(&log_path), modified(&init_environ_path)) {
+            (Ok(log_modified), Ok(boot_time)) => log_modified > boot_time,
+            // Without a log, provisioning hasn't got far enough to write one
+            _ => true,
+        }
+    }
+
+    // Look inside a block device (e.g. "/dev/vdb"), mounting it if needed
+    fn inspect_device<T>(&self, devname: &str, inspect: impl FnOnce(&Path) -> T) -> Option<T> {
+        let mut device_path = self.path_root.clone();
+        device_path.push(devname.trim_start_matches('/'));
+        mount::with_mounted(&device_path, self.path_root == Path::new("/"), inspect)
+    }
+
+    // Firmware detection; UEFI implies e.g. a Generation 2 Hyper-V VM
+    fn is_efi(&self) -> bool {
+        let mut efi_path = self.path_root.clone();
+        efi_path.push("sys/firmware/efi");
+        efi_path.exists()
+    }
+
+    fn is_hyperv_without_azure(&mut self) -> bool {
+        self.dmi_sys_vendor() == &Some("Microsoft Corporation".to_string())
+            && !self.is_azure_chassis()
+    }
+
+    fn get_cdrom_devices(&self) -> Vec<PathBuf> {
+        let mut dev_path = self.path_root.clone();
+        dev_path.push("dev");
+        let mut cdrom_devices: Vec<PathBuf> = sorted_dir_entries(&dev_path)
+            .into_iter()
+            .filter(|device| {
+                device
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_prefix("sr"))
+                    .map(|suffix| !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit()))
+                    .unwrap_or(false)
+            })
+            .collect();
+        // /dev/cdrom is usually a symlink to one of the above, so only add it if it isn't
+        let cdrom_path = dev_path.join("cdrom");
+        if let Ok(cdrom_target) = cdrom_path.canonicalize() {
+            let already_found = cdrom_devices
+                .iter()
+                .any(|device| device.canonicalize().ok().as_ref() == Some(&cdrom_target));
+            if !already_found {
+                cdrom_devices.push(cdrom_path);
+            }
+        }
+        cdrom_devices
+    }
+
+    fn cdrom_has_label(&self, matches: impl Fn(&str) -> bool) -> bool {
+        self.get_cdrom_devices().iter().any(|device| {
+            devices::read_iso9660_label(device)
+                .map(|label| matches(&label))
+                .unwrap_or(false)
+        })
+    }
+
+    // ISO9660 names are upper case (and can't contain '-') unless extensions are in use, so
+    // compare loosely
+    fn cdrom_has_root_file(&self, filename: &str) -> bool {
+        let normalise = |name: &str| name.to_ascii_lowercase().replace('_', "-");
+        self.get_cdrom_devices().iter().any(|device| {
+            devices::list_iso9660_root(device)
+                .unwrap_or_default()
+                .iter()
+                .any(|name| normalise(name) == normalise(filename))
+        })
+    }
+
+    fn chassis_asset_tag_datasource(&mut self) -> Option<&'static str> {
+        let asset_tag = self.dmi_chassis_asset_tag().as_ref()?;
+        CHASSIS_ASSET_TAG_DATASOURCES
+            .iter()
+            .find(|(pattern, _)| match pattern.strip_suffix('*') {
+                Some(prefix) => asset_tag.starts_with(prefix),
+                None => asset_tag == pattern,
+            })
+            .map(|(_, datasource)| *datasource)
+    }
+
+    fn is_iscsi_root(&mut self) -> bool {
+        self.get_kernel_cmdline()
+            .split_whitespace()
+            .any(|token| token.starts_with("iscsi_") || token.starts_with("rd.iscsi."))
+    }
+
+    fn has_initramfs_network_config(&self) -> bool {
+        let mut run_path = self.path_root.clone();
+        run_path.push("run");
+        let has_klibc_config = sorted_dir_entries(&run_path).iter().any(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("net-") && name.ends_with(".conf"))
+                .unwrap_or(false)
+        });
+        let mut open_iscsi_path = run_path;
+        open_iscsi_path.push("initramfs/open-iscsi.interface");
+        has_klibc_config || open_iscsi_path.exists()
+    }
+
+    fn seed_dir_path(&self, prefix: Option<&str>, seed_type: &str) -> PathBuf {
         let mut seed_path = self.path_root.clone();
         if let Some(prefix) = prefix {
             seed_path.push(prefix);
         }
         seed_path.push("var/lib/cloud/seed");
         seed_path.push(seed_type);
-        seed_path.push(filename);
-        seed_path.exists()
+        seed_path
     }
 
-    // Datasource checks
-    #[allow(non_snake_case)]
-    fn dscheck_AliYun(&mut self) -> bool {
-        // TEST GAP: seed directory checks
-        self.dmi_product_name() == &Some("Alibaba Cloud ECS".to_string())
+    fn seed_dir_exists(&self, prefix: Option<&str>, seed_type: &str) -> bool {
+        self.seed_dir_path(prefix, seed_type).is_dir()
     }
 
-    #[allow(non_snake_case)]
-    fn dscheck_Azure(&mut self) -> bool {
-        if self.seed_path_exists(None, "azure", "ovf-env.xml") {
-            return true;
-        }
-        self.dmi_chassis_asset_tag() == &Some("7783-7084-3265-9085-8269-3286-77".to_string())
+    fn seed_path_exists(&self, prefix: Option<&str>, seed_type: &str, filename: &str) -> bool {
+        let mut seed_path = self.seed_dir_path(prefix, seed_type);
+        seed_path.push(filename);
+        seed_path.exists()
     }
 
-    #[allow(non_snake_case)]
-    fn dscheck_ConfigDrive(&self) -> bool {
-        self.seed_path_exists(None, "config_drive", "openstack/latest/meta_data.json")
+    // Ec2 platform identification
+    fn ec2_identify_platform(&mut self) -> Option<&'static str> {
+        // Ec2-compatible clouds are checked before AWS itself, so that they win when their DMI
+        // data would also satisfy the AWS heuristics
+        if self
+            .dmi_product_serial()
+            .as_ref()
+            .map(|serial| serial.ends_with(".brightbox.com"))
+            .unwrap_or(false)
+        {
+            return Some("Brightbox");
+        }
+        if self.ec2_is_zstack() {
+            return Some("ZStack");
+        }
+        if self.dmi_sys_vendor() == &Some("e24cloud".to_string())
+            || self.dmi_asset_tag_matches(|tag| tag == "e24cloud")
+        {
+            return Some("E24cloud");
+        }
+        if self.dmi_product_name() == &Some("3DS Outscale VM".to_string())
+            && (self.dmi_sys_vendor() == &Some("3DS Outscale".to_string())
+                || self.dmi_asset_tag_matches(|tag| tag == "3DS Outscale"))
+        {
+            return Some("Outscale");
+        }
+        let equinix_vendors = [
+            Some("Packet".to_string()),
+            Some("Equinix Metal".to_string()),
+        ];
+        if equinix_vendors.contains(self.dmi_sys_vendor()) {
+            return Some("Equinix Metal");
+        }
+        if self
+            .dmi_sys_vendor()
+            .as_ref()
+            .map(|vendor| vendor.starts_with("Samsung Cloud Platform"))
+            .unwrap_or(false)
+        {
+            return Some("Samsung Cloud Platform");
+        }
+        if self.ec2_is_aws() {
+            return Some("AWS");
+        }
+        None
     }
 
-    #[allow(non_snake_case)]
-    fn dscheck_Ec2(&mut self) -> bool {
+    fn ec2_is_aws(&mut self) -> bool {
         // TEST_GAP: One of serial or UUID can be missing
         // TEST GAP: Serial and UUID equality is not exercised
+        if self.is_dmi_absent() || self.dmi_product_uuid().is_none() {
+            // Xen PV guests have no DMI (and product_uuid may be unreadable anyway), but the
+            // hypervisor still exposes the instance's UUID
+            if !self.is_xen() {
+                return false;
+            }
+            let mut hypervisor_uuid_path = self.path_root.clone();
+            hypervisor_uuid_path.push("sys/hypervisor/uuid");
+            return std::fs::read_to_string(&hypervisor_uuid_path)
+                .map(|uuid| uuid.trim().to_ascii_lowercase().starts_with("ec2"))
+                .unwrap_or(false);
+        }
         let serial = self
             .dmi_product_serial()
             .as_ref()
             .map(|s| s.to_ascii_lowercase());
+        // Some Xen hosts present the UUID byte-swapped (e.g. 45e12aec-...), so use whichever
+        // ordering identifies as EC2
         let uuid = self
             .dmi_product_uuid()
             .as_ref()
-            .map(|s| s.to_ascii_lowercase());
-        serial
+            .map(|s| s.to_ascii_lowercase())
+            .map(|s| match uuid_swap_endianness(&s) {
+                Some(swapped) if swapped.starts_with("ec2") => swapped,
+                _ => s,
+            });
+        let uuid_is_ec2 = uuid.as_ref().map(|s| s.starts_with("ec2")).unwrap_or(false);
+        if serial
             .as_ref()
             .map(|s| s.starts_with("ec2"))
             .unwrap_or(false)
-            && uuid.as_ref().map(|s| s.starts_with("ec2")).unwrap_or(false)
+            && uuid_is_ec2
             && serial == uuid
+        {
+            return true;
+        }
+        // Nitro instances don't reliably report an ec2 serial, but do brand the BIOS
+        // TEST GAP: Nitro instances are not exercised
+        uuid_is_ec2 && self.dmi_bios_vendor() == &Some("Amazon EC2".to_string())
+    }
+
+    // Some platforms stamp their identity on the baseboard rather than the chassis
+    fn dmi_asset_tag_matches(&mut self, matches: impl Fn(&str) -> bool) -> bool {
+        self.dmi_chassis_asset_tag().as_deref().map(&matches) == Some(true)
+            || self.dmi_board_asset_tag().as_deref().map(&matches) == Some(true)
+    }
+
+    fn ec2_is_zstack(&mut self) -> bool {
+        self.dmi_asset_tag_matches(|tag| tag.ends_with(".zstack.io"))
     }
 
+    // Datasource checks
     #[allow(non_snake_case)]
-    fn dscheck_Exoscale(&mut self) -> bool {
-        // TEST GAP: I didn't need to implement Exoscale support
-        self.dmi_product_name() == &Some("Exoscale".to_string())
+    fn dscheck_Akamai(&mut self) -> bool {
+        let vendors = [Some("Akamai".to_string()), Some("Linode".to_string())];
+        vendors.contains(self.dmi_sys_vendor())
     }
 
     #[allow(non_snake_case)]
-    fn dscheck_GCE(&mut self) -> bool {
-        self.dmi_product_name() == &Some("Google Compute Engine".to_string())
-            || self
-                .dmi_product_serial()
-                .as_ref()
-                .map(|serial| serial.starts_with("GoogleCloud"))
-                .unwrap_or(false)
+    fn dscheck_AliYun(&mut self) -> bool {
+        if self.seed_path_exists(None, "AliYun", "meta-data")
+            && self.seed_path_exists(None, "AliYun", "user-data")
+        {
+            return true;
+        }
+        self.dmi_product_name() == &Some("Alibaba Cloud ECS".to_string())
     }
 
     #[allow(non_snake_case)]
-    fn dscheck_NoCloud(&self) -> bool {
-        // TEST GAP: nocloud and nocloud-net are not tested for both writable and regular paths
-        for seed_type in &["nocloud", "nocloud-net"] {
-            if self.seed_path_exists(None, seed_type, "user-data")
-                && self.seed_path_exists(None, seed_type, "meta-data")
-            {
-                return true;
+    fn dscheck_AltCloud(&mut self) -> bool {
+        // An explicit cloud type always wins over DMI
+        let mut cloud_info_path = self.path_root.clone();
+        cloud_info_path.push("etc/sysconfig/cloud-info");
+        if let Ok(cloud_info) = std::fs::read_to_string(&cloud_info_path) {
+            let cloud_type = cloud_info.trim().to_ascii_uppercase();
+            return cloud_type == "RHEV" || cloud_type == "VSPHERE";
+        }
+        // Without the marker, only RHEV is distinctive enough to match; every VMware guest
+        // reports "VMware Virtual Platform"
+        if self
+            .dmi_product_name()
+            .as_ref()
+            .map(|name| name.starts_with("RHEV Hypervisor"))
+            .unwrap_or(false)
+        {
+            return true;
+        }
+        // Otherwise, if asked to, look for the user data payload itself, which RHEV delivers on
+        // a floppy and vSphere on a CD-ROM
+        // TEST GAP: payload probing is not exercised
+        if !self.altcloud_probe_payload {
+            return false;
+        }
+        match self.get_virt() {
+            virt::Virt::Kvm => {
+                let mut floppy_path = self.path_root.clone();
+                floppy_path.push("dev/fd0");
+                devices::list_fat_root(&floppy_path)
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(ALTCLOUD_USER_DATA))
             }
+            virt::Virt::VMware => self.cdrom_has_root_file(ALTCLOUD_USER_DATA),
+            _ => false,
+        }
+    }
 
-            if self.seed_path_exists(Some("writable/system-data"), seed_type, "user-data")
-                && self.seed_path_exists(Some("writable/system-data"), seed_type, "meta-data")
-            {
-                return true;
-            }
+    #[allow(non_snake_case)]
+    fn dscheck_Azure(&mut self) -> bool {
+        if self.seed_path_exists(None, "azure", "ovf-env.xml") {
+            return true;
+        }
+        if self.is_azure_chassis() {
+            return true;
+        }
+        // Everything else reads devices or the network, so only bother on Hyper-V
+        if self.dmi_sys_vendor() != &Some("Microsoft Corporation".to_string()) {
+            return false;
+        }
+        // Azure Stack and some custom images lack the asset tag, but still attach the
+        // provisioning ISO
+        // TEST GAP: provisioning ISO detection is not exercised
+        if self.has_fs_with_label_prefix("rd_rdfe_")
+            || self.cdrom_has_label(|label| label.starts_with("rd_rdfe_"))
+        {
+            return true;
+        }
+        if self.get_cdrom_devices().iter().any(|device| {
+            file_contains_ignore_case(
+                device,
+                "http://schemas.microsoft.com/windowsazure",
+                10 * 1024 * 1024,
+            )
+        }) {
+            return true;
         }
-        false
+        // Azure Stack HCI has neither, but does run IMDS
+        // TEST GAP: IMDS probing is not exercised
+        self.probe_metadata_service(
+            METADATA_SERVICE_HOST,
+            "/metadata/instance?api-version=2021-02-01",
+            &[("Metadata", "true")],
+        )
+        .unwrap_or(false)
     }
 
     #[allow(non_snake_case)]
-    fn dscheck_Oracle(&mut self) -> bool {
-        self.dmi_chassis_asset_tag() == &Some("OracleCloud.com".to_string())
+    fn dscheck_Bigstep(&self) -> bool {
+        // Bigstep's seed lives under data/, not in the usual seed directory
+        let mut url_path = self.path_root.clone();
+        url_path.push("var/lib/cloud/data/seed/bigstep/url");
+        url_path.exists()
     }
 
-    // Output
-    fn write_cfg_out(self, datasource_list: Vec<String>) {
-        create_dir_all(self.cfg_out.parent().unwrap()).unwrap();
-        let mut file = match File::create(&self.cfg_out) {
-            Err(why) => panic!(
-                "couldn't create {}: {}",
-                self.cfg_out.display(),
-                why.to_string()
-            ),
-            Ok(file) => file,
-        };
-        let mut map = BTreeMap::new();
-        map.insert("datasource_list".to_string(), datasource_list);
-        if file
-            .write_all(serde_yaml::to_string(&map).unwrap().as_bytes())
-            .is_err()
-        {
-            std::process::exit(1);
-        };
+    #[allow(non_snake_case)]
+    fn dscheck_CloudSigma(&mut self) -> bool {
+        self.dmi_product_name() == &Some("CloudSigma".to_string())
     }
 
-    fn get_datasource_list_from_path(&self, path: &PathBuf) -> Option<Vec<String>> {
-        let file = match File::open(&path) {
-            Err(_) => return None,
-            Ok(file) => file,
-        };
-        let config: serde_yaml::Mapping = match serde_yaml::from_reader(file) {
-            Err(_) => return None,
-            Ok(result) => result,
+    #[allow(non_snake_case)]
+    fn dscheck_CloudStack(&mut self) -> policy::CheckResult {
+        if let Some(product_name) = self.dmi_product_name() {
+            return product_name.starts_with("CloudStack").into();
+        }
+        // Xen PV guests have no DMI data, so look for the virtual router that CloudStack hands
+        // out as the DHCP server instead
+        if !self.is_dmi_absent() || !self.is_xen() {
+            return policy::CheckResult::NotFound;
+        }
+        let virtual_router = match leases::find_dhcp_server(&self.path_root) {
+            Some(virtual_router) => virtual_router,
+            None => return policy::CheckResult::NotFound,
         };
-        config
-            .get(&serde_yaml::Value::from("datasource_list"))
-            .map(|datasource_list| {
-                datasource_list
-                    .as_sequence()
-                    .unwrap()
-                    .iter()
-                    .filter_map(|value| value.as_str().map(|s| s.to_string()))
-                    .collect()
-            })
+        println!("CloudStack virtual router: {}", virtual_router);
+        // Any DHCP server would match, so confirm it serves metadata if we can
+        match self.probe_metadata_service(&virtual_router, "/latest/meta-data/instance-id", &[]) {
+            Some(found) => found.into(),
+            None => policy::CheckResult::Maybe,
+        }
     }
 
-    fn get_datasource_list(&self) -> Vec<String> {
-        // Set up all our paths first
+    #[allow(non_snake_case)]
+    fn dscheck_ConfigDrive(&mut self) -> bool {
+        if self.seed_path_exists(None, "config_drive", "openstack/latest/meta_data.json") {
+            return true;
+        }
+        // OpenStack attaches config drives as vfat or iso9660 volumes labelled config-2, but the
+        // case of the label varies between the two
+        let config_drives: Vec<String> = self
+            .get_block_devices()
+            .iter()
+            .filter(|device| {
+                device
+                    .label
+                    .as_ref()
+                    .map(|label| label.eq_ignore_ascii_case("config-2"))
+                    .unwrap_or(false)
+            })
+            .map(|device| device.devname.clone())
+            .collect();
+        // Where we can look inside, make sure it really is a config drive
+        for devname in config_drives {
+            let has_metadata = self.inspect_device(&devname, |contents| {
+                contents.join("openstack/latest/meta_data.json").exists()
+                    || contents.join("ec2/latest/meta-data.json").exists()
+            });
+            if has_metadata.unwrap_or(true) {
+                return true;
+            }
+            println!("{} is labelled config-2, but has no metadata", devname);
+        }
+        // Nutanix AHV always provides its metadata on a config drive
+        self.dmi_product_name() == &Some("AHV".to_string())
+            && self.dmi_sys_vendor() == &Some("Nutanix".to_string())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_DigitalOcean(&mut self) -> bool {
+        self.dmi_sys_vendor() == &Some("DigitalOcean".to_string())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_Ec2(&mut self) -> bool {
+        if self.seed_path_exists(None, "ec2", "meta-data")
+            && self.seed_path_exists(None, "ec2", "user-data")
+        {
+            return true;
+        }
+        // Containers can see their host's hypervisor, which isn't the container's platform
+        if self.is_container() {
+            return false;
+        }
+        match self.ec2_identify_platform() {
+            Some(platform) => {
+                println!("Ec2 platform: {}", platform);
+                let amazon = Some("Amazon EC2".to_string());
+                if platform != "AWS"
+                    || !self.ec2_require_imds
+                    || self.dmi_sys_vendor() == &amazon
+                    || self.dmi_bios_vendor() == &amazon
+                {
+                    return true;
+                }
+                match self.probe_ec2_imds() {
+                    Some(found) => found,
+                    None => {
+                        println!("Ec2 confirmation required, but network probing is unavailable");
+                        false
+                    }
+                }
+            }
+            None => false,
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_Exoscale(&mut self) -> bool {
+        if self.seed_path_exists(None, "Exoscale", "meta-data")
+            && self.seed_path_exists(None, "Exoscale", "user-data")
+        {
+            return true;
+        }
+        self.dmi_product_name() == &Some("Exoscale".to_string())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_GCE(&mut self) -> policy::CheckResult {
+        let gce_product_name =
+            self.dmi_product_name() == &Some("Google Compute Engine".to_string());
+        let gce_serial = self
+            .dmi_product_serial()
+            .as_ref()
+            .map(|serial| serial.starts_with("GoogleCloud"))
+            .unwrap_or(false);
+        if gce_product_name && gce_serial {
+            return policy::CheckResult::Found;
+        }
+        // Newer machine types and Confidential VMs don't always set the product name
+        let matched = gce_product_name
+            || gce_serial
+            || self.dmi_sys_vendor() == &Some("Google".to_string())
+            || self.dmi_board_name() == &Some("Google Compute Engine".to_string());
+        // Any one of those is easily mimicked (e.g. by labs nesting cloned GCE images), so
+        // confirm with the metadata server if we can
+        // TEST GAP: metadata server probing is not exercised
+        if !matched {
+            return policy::CheckResult::NotFound;
+        }
+        match self.probe_metadata_service(
+            METADATA_SERVICE_HOST,
+            "/computeMetadata/v1/instance/id",
+            &[("Metadata-Flavor", "Google")],
+        ) {
+            Some(found) => found.into(),
+            None => policy::CheckResult::Maybe,
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_Gandi(&mut self) -> bool {
+        self.dmi_sys_vendor() == &Some("Gandi".to_string())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_Hetzner(&mut self) -> bool {
+        if self.dmi_sys_vendor() == &Some("Hetzner".to_string()) {
+            return true;
+        }
+        // Older Hetzner hosts didn't set sys_vendor, but did brand the product name
+        self.dmi_product_name()
+            .as_ref()
+            .map(|name| name.starts_with("Hetzner"))
+            .unwrap_or(false)
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_LXD(&mut self) -> bool {
+        // lxd-agent (or LXD itself, for containers) provides the devlxd socket, which Incus
+        // renamed
+        for socket in &["dev/lxd/sock", "dev/incus/sock"] {
+            let mut socket_path = self.path_root.clone();
+            socket_path.push(socket);
+            if socket_path.exists() {
+                return true;
+            }
+        }
+        // In VMs the agent may not be running yet, but the firmware identifies the platform
+        let lxd_board_names = [Some("LXD".to_string()), Some("Incus".to_string())];
+        self.get_virt() == &virt::Virt::Kvm && lxd_board_names.contains(self.dmi_board_name())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_MAAS(&mut self) -> bool {
+        // Ephemeral MAAS environments boot from iSCSI and pass a cloud-config-url
+        let maas_iqn = "iqn.2004-05.com.ubuntu:maas";
+        let cmdline = self.get_kernel_cmdline();
+        if cmdline.contains(maas_iqn) && cmdline.contains("cloud-config-url=") {
+            return true;
+        }
+
+        if self.seed_path_exists(None, "maas", "meta-data/instance-id") {
+            return true;
+        }
+
+        // Installed systems have MAAS credentials written into their configuration by MAAS
+        let datasource_key = serde_yaml::Value::from("datasource");
+        let maas_key = serde_yaml::Value::from("MAAS");
+        self.get_cloud_config_paths().iter().any(|config_path| {
+            self.read_cloud_config(config_path)
+                .and_then(|config| config.get(&datasource_key).cloned())
+                .and_then(|datasource| datasource.get(&maas_key).cloned())
+                .map(|maas_config| {
+                    maas_config.get("metadata_url").is_some()
+                        || maas_config.get("consumer_key").is_some()
+                })
+                .unwrap_or(false)
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_NWCS(&mut self) -> bool {
+        self.dmi_sys_vendor() == &Some("NWCS".to_string())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_NoCloud(&mut self) -> bool {
+        // QEMU users can pass a seed in SMBIOS, e.g. -smbios type=1,serial=ds=nocloud;s=URL; as
+        // serials are free text, don't trust them anywhere else (unless we can't tell)
+        let smbios_seed_possible = matches!(self.get_virt(), virt::Virt::Kvm | virt::Virt::Unknown);
+        let serials = if smbios_seed_possible {
+            vec![
+                self.dmi_product_serial().clone(),
+                self.dmi_board_serial().clone(),
+            ]
+        } else {
+            vec![]
+        };
+        for serial in serials.iter().flatten() {
+            let datasource_arg = match cmdline::find_datasource_arg(serial) {
+                Some(datasource_arg) => datasource_arg,
+                None => continue,
+            };
+            if canonical_datasource_name(&datasource_arg.name) != "NoCloud" {
+                continue;
+            }
+            if let Some((_, seedfrom)) = datasource_arg
+                .options
+                .iter()
+                .find(|(key, _)| key == "s" || key == "seedfrom")
+            {
+                self.set_datasource_config("NoCloud", "seedfrom", seedfrom);
+            }
+            return true;
+        }
+
+        // TEST GAP: nocloud and nocloud-net are not tested for both writable and regular paths
+        for seed_type in &["nocloud", "nocloud-net"] {
+            if self.seed_path_exists(None, seed_type, "user-data")
+                && self.seed_path_exists(None, seed_type, "meta-data")
+            {
+                return true;
+            }
+
+            if self.seed_path_exists(Some("writable/system-data"), seed_type, "user-data")
+                && self.seed_path_exists(Some("writable/system-data"), seed_type, "meta-data")
+            {
+                return true;
+            }
+        }
+        self.has_fs_with_label(&["cidata", "CIDATA"])
+            || self.has_partition_with_label(&["cidata", "CIDATA"])
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_OVF(&mut self) -> bool {
+        if self.seed_path_exists(None, "ovf", "ovf-env.xml") {
+            return true;
+        }
+        // OVF environments are only ever attached to virtual machines
+        if self.get_virt() == &virt::Virt::None {
+            return false;
+        }
+        // Azure also attaches an OVF environment, so don't claim it
+        if self.is_azure_chassis() {
+            return false;
+        }
+        if self.has_fs_with_label(OVF_LABELS) {
+            return true;
+        }
+        // TEST GAP: CD-ROMs probed directly are not exercised
+        if self.cdrom_has_label(|label| OVF_LABELS.contains(&label))
+            || self.cdrom_has_root_file("ovf-env.xml")
+        {
+            return true;
+        }
+        self.get_cdrom_devices().iter().any(|device| {
+            file_contains_ignore_case(
+                device,
+                "http://schemas.dmtf.org/ovf/environment/1",
+                10 * 1024 * 1024,
+            )
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_OpenNebula(&mut self) -> bool {
+        if self.seed_dir_exists(None, "opennebula") {
+            return true;
+        }
+        self.has_fs_with_label(&["CONTEXT", "CDROM"])
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_OpenStack(&mut self) -> policy::CheckResult {
+        if self.seed_path_exists(None, "openstack", "latest/meta_data.json") {
+            return policy::CheckResult::Found;
+        }
+        let openstack_names = [
+            Some("OpenStack Nova".to_string()),
+            Some("OpenStack Compute".to_string()),
+        ];
+        if openstack_names.contains(self.dmi_product_name())
+            || self.chassis_asset_tag_datasource() == Some("OpenStack")
+        {
+            return policy::CheckResult::Found;
+        }
+        // Civo serves an OpenStack-compatible metadata service
+        if self.dmi_sys_vendor() == &Some("Civo".to_string()) {
+            return policy::CheckResult::Found;
+        }
+        // PowerVC (OpenStack on PowerVM) guests have no SMBIOS at all, so being an LPAR is the
+        // best signal available; as upstream, that's only enough for a maybe
+        // TEST GAP: ppc64el is not exercised
+        if self.is_powervm_lpar() {
+            return policy::CheckResult::Maybe;
+        }
+        // As upstream, a config drive is preferred over the network, so only ask the metadata
+        // service when there isn't one
+        // TEST GAP: metadata service probing is not exercised
+        if self.is_container() || self.has_fs_with_label_ignore_case("config-2") {
+            return policy::CheckResult::NotFound;
+        }
+        self.probe_metadata_service(METADATA_SERVICE_HOST, "/openstack", &[])
+            .unwrap_or(false)
+            .into()
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_Oracle(&mut self) -> bool {
+        if self.dmi_chassis_asset_tag() == &Some("OracleCloud.com".to_string()) {
+            return true;
+        }
+        // Bare metal shapes don't carry the asset tag, but do boot from iSCSI with their network
+        // configured in the initramfs
+        if self.dmi_sys_vendor() != &Some("Oracle Corporation".to_string()) {
+            return false;
+        }
+        if self.is_iscsi_root() && self.has_initramfs_network_config() {
+            return true;
+        }
+        // Otherwise, IMDS v2 can still tell an OCI shape from any other Oracle hardware
+        self.probe_metadata_service(
+            METADATA_SERVICE_HOST,
+            "/opc/v2/instance/",
+            &[("Authorization", "Bearer Oracle")],
+        )
+        .unwrap_or(false)
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_RbxCloud(&mut self) -> bool {
+        self.has_fs_with_label(&["CLOUDMD", "cloudmd"])
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_Scaleway(&mut self) -> bool {
+        if self.dmi_sys_vendor() == &Some("Scaleway".to_string()) {
+            return true;
+        }
+        if self.kernel_cmdline_has_token("scaleway") {
+            return true;
+        }
+        let mut marker_path = self.path_root.clone();
+        marker_path.push("var/run/scaleway");
+        marker_path.exists()
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_SmartOS(&mut self) -> bool {
+        // KVM guests on Joyent/SmartOS report "SmartDC HVM"
+        if self
+            .dmi_product_name()
+            .as_ref()
+            .map(|name| name.starts_with("SmartDC"))
+            .unwrap_or(false)
+        {
+            return true;
+        }
+        // Guests which don't brand their product name still have the serial port the metadata
+        // agent talks over
+        // TEST GAP: unbranded KVM guests are not exercised
+        if self.dmi_sys_vendor() == &Some("Joyent".to_string()) {
+            let serial_device = self
+                .get_datasource_setting("SmartOS", "serial_device")
+                .unwrap_or_else(|| "/dev/ttyS1".to_string());
+            let mut serial_device_path = self.path_root.clone();
+            serial_device_path.push(serial_device.trim_start_matches('/'));
+            if serial_device_path.exists() {
+                return true;
+            }
+        }
+        // LX-branded zones have no DMI data, but do have the zone's metadata socket
+        // TEST GAP: LX-brand zones are not exercised
+        let mut metadata_sockfile = self.path_root.clone();
+        metadata_sockfile.push("native/.zonecontrol/metadata.sock");
+        if metadata_sockfile.exists() {
+            return true;
+        }
+        // The socket may not be there yet, but the zone always has the illumos /native tree and
+        // a distinctive kernel version
+        let mut native_path = self.path_root.clone();
+        native_path.push("native");
+        native_path.is_dir() && self.get_uname().kernel_version.starts_with("BrandZ")
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_TencentCloud(&mut self) -> bool {
+        let tencent = Some("Tencent Cloud".to_string());
+        self.dmi_sys_vendor() == &tencent || self.dmi_chassis_asset_tag() == &tencent
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_UpCloud(&mut self) -> bool {
+        self.dmi_sys_vendor() == &Some("UpCloud".to_string())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_VMware(&mut self) -> bool {
+        if self.dmi_sys_vendor() != &Some("VMware, Inc.".to_string()) {
+            return false;
+        }
+        if self.vmware_skip_rpc {
+            return true;
+        }
+        // The RPC tools talk to the hypervisor we're actually running on, which says nothing about
+        // a tree under another PATH_ROOT
+        if self.path_root != Path::new("/") {
+            return false;
+        }
+        // Only claim the platform if the guestinfo table actually holds data for the datasource
+        // TEST GAP: the guestinfo transport is not exercised
+        ["metadata", "userdata", "vendordata"]
+            .iter()
+            .any(|key| vmware_guestinfo_get(key).is_some())
+    }
+
+    #[allow(non_snake_case)]
+    fn dscheck_Vultr(&mut self) -> bool {
+        if self.dmi_sys_vendor() == &Some("Vultr".to_string()) {
+            return true;
+        }
+        if self.kernel_cmdline_has_token("vultr") {
+            return true;
+        }
+        let mut marker_path = self.path_root.clone();
+        marker_path.push("etc/vultr");
+        marker_path.exists()
+    }
+
+    // Output
+    fn set_datasource_config(&mut self, datasource: &str, key: &str, value: &str) {
+        let datasource_key = serde_yaml::Value::from(datasource);
+        if !self.datasource_config.contains_key(&datasource_key) {
+            self.datasource_config.insert(
+                datasource_key.clone(),
+                serde_yaml::Value::Mapping(serde_yaml::Mapping::new()),
+            );
+        }
+        if let Some(serde_yaml::Value::Mapping(config)) =
+            self.datasource_config.get_mut(&datasource_key)
+        {
+            config.insert(serde_yaml::Value::from(key), serde_yaml::Value::from(value));
+        }
+    }
+
+    fn write_cfg_out(self, datasource_list: Vec<String>) {
+        let content = match self.policy.mode {
+            policy::Mode::Report => self.render_report(datasource_list),
+            _ => self.render_config(datasource_list),
+        };
+        create_dir_all(self.cfg_out.parent().unwrap()).unwrap();
+        let mut file = match File::create(&self.cfg_out) {
+            Err(why) => panic!("couldn't create {}: {}", self.cfg_out.display(), why),
+            Ok(file) => file,
+        };
+        if file.write_all(content.as_bytes()).is_err() {
+            std::process::exit(1);
+        };
+    }
+
+    // The config cloud-init will use
+    fn render_config(&self, datasource_list: Vec<String>) -> String {
+        let mut map = BTreeMap::new();
+        map.insert(
+            "datasource_list".to_string(),
+            serde_yaml::to_value(datasource_list).unwrap(),
+        );
+        if !self.datasource_config.is_empty() {
+            map.insert(
+                "datasource".to_string(),
+                serde_yaml::Value::Mapping(self.datasource_config.clone()),
+            );
+        }
+        serde_yaml::to_string(&map).unwrap()
+    }
+
+    // What would have been chosen, and why, entirely commented out so cloud-init behaves as if
+    // we hadn't run
+    fn render_report(&self, datasource_list: Vec<String>) -> String {
+        let mut report = vec![
+            "rs-identify report; this file does not configure cloud-init".to_string(),
+            format!("policy: {}", self.policy),
+            "checks:".to_string(),
+        ];
+        if self.check_results.is_empty() {
+            report.push("  (none run)".to_string());
+        }
+        for (datasource, result) in &self.check_results {
+            report.push(format!("  {}: {}", datasource, result));
+        }
+        report.push("config that would have been written:".to_string());
+        report.extend(
+            self.render_config(datasource_list)
+                .lines()
+                .map(|line| line.to_string()),
+        );
+        report.iter().map(|line| format!("# {}\n", line)).collect()
+    }
+
+    fn get_cloud_config_paths(&self) -> Vec<PathBuf> {
         let mut etc_cloud_path = self.path_root.clone();
         etc_cloud_path.push("etc/cloud/cloud.cfg");
         let mut etc_cloud_d_path = self.path_root.clone();
         etc_cloud_d_path.push("etc/cloud/cloud.cfg.d");
-        let mut cloud_d_paths: Vec<PathBuf> = match std::fs::read_dir(etc_cloud_d_path) {
-            Err(_) => vec![],
-            Ok(read_dir) => read_dir
-                .filter_map(|dir_entry| dir_entry.ok().map(|dir_entry| dir_entry.path()))
-                .collect(),
-        };
-        cloud_d_paths.sort();
 
+        let mut paths = vec![etc_cloud_path];
+        // As cloud-init, ignore anything else (editor backups, .dpkg-old, .rpmsave, ...)
+        paths.extend(
+            sorted_dir_entries(&etc_cloud_d_path)
+                .into_iter()
+                .filter(|path| path.extension().is_some_and(|ext| ext == "cfg"))
+                // Following symlinks, so dangling ones fail to stat
+                .filter(|path| match std::fs::metadata(path) {
+                    Err(_) => {
+                        println!("Skipping {}: cannot stat", path.display());
+                        false
+                    }
+                    Ok(metadata) if !metadata.is_file() => {
+                        println!("Skipping {}: not a regular file", path.display());
+                        false
+                    }
+                    Ok(_) => true,
+                }),
+        );
+        paths
+    }
+
+    fn read_cloud_config(&self, path: &PathBuf) -> Option<serde_yaml::Mapping> {
+        let content = std::fs::read_to_string(path).ok()?;
+        // The header itself is a YAML comment, but cloud-init renders the rest later with
+        // variables we don't have, so only templates without any template syntax are usable
+        let is_jinja = content.lines().next().is_some_and(|line| {
+            line.strip_prefix("## template:")
+                .is_some_and(|renderer| renderer.trim().eq_ignore_ascii_case("jinja"))
+        });
+        if is_jinja
+            && ["{{", "{%", "{#"]
+                .iter()
+                .any(|syntax| content.contains(syntax))
+        {
+            println!(
+                "{} is a jinja template, which can't be rendered here; ignoring it",
+                path.display()
+            );
+            return None;
+        }
+        serde_yaml::from_str(&content).ok()
+    }
+
+    fn get_list_from_path(&self, path: &PathBuf, key: &str) -> Option<Vec<String>> {
+        let config = self.read_cloud_config(path)?;
+        match config.get(&serde_yaml::Value::from(key))? {
+            serde_yaml::Value::Sequence(list) => Some(
+                list.iter()
+                    .filter_map(|value| value.as_str().map(|s| s.to_string()))
+                    .collect(),
+            ),
+            // A lone name is a list of one
+            serde_yaml::Value::String(value) => Some(vec![value.to_string()]),
+            _ => {
+                println!(
+                    "Ignoring {} in {}: not a list of names",
+                    key,
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    // A datasource's own setting (datasource.<name>.<key>), from the last config to set it
+    fn get_datasource_setting(&self, datasource: &str, key: &str) -> Option<String> {
+        let mut setting = None;
+        for config_path in self.get_cloud_config_paths() {
+            let value = self.read_cloud_config(&config_path).and_then(|config| {
+                config
+                    .get(&serde_yaml::Value::from("datasource"))?
+                    .get(datasource)?
+                    .get(key)?
+                    .as_str()
+                    .map(|value| value.to_string())
+            });
+            setting = value.or(setting);
+        }
+        setting
+    }
+
+    // The policy string from ds-identify.cfg applies over the default
+    fn get_policy(&mut self) -> policy::Policy {
+        let mut policy = policy::Policy::default();
+        if let Some(cfg_policy) = self.ds_identify_cfg.get("policy") {
+            if let Err(error) = policy.apply(cfg_policy) {
+                println!("Ignoring policy in ds-identify.cfg: {}", error);
+            }
+        }
+        // The kernel command line takes precedence, for debugging without editing the image
+        if let Some(cmdline_policy) = cmdline::find_policy_arg(self.get_kernel_cmdline()) {
+            if let Err(error) = policy.apply(cmdline_policy) {
+                println!(
+                    "Ignoring ci.di.policy on the kernel command line: {}",
+                    error
+                );
+            }
+        }
+        policy
+    }
+
+    fn get_datasource_list(&self) -> Vec<String> {
         // Find the latest definition of datasource_list and use that
         // TEST GAP: the tests don't exercise checking cloud.cfg itself
-        let mut list = self.get_datasource_list_from_path(&etc_cloud_path);
-        for cloud_d_path in cloud_d_paths {
-            list = self.get_datasource_list_from_path(&cloud_d_path).or(list);
+        let mut list = None;
+        for config_path in self.get_cloud_config_paths() {
+            list = self
+                .get_list_from_path(&config_path, "datasource_list")
+                .or(list);
         }
         list.unwrap_or_else(|| {
-            vec![
-                "AliYun".to_string(),
-                "Azure".to_string(),
-                "ConfigDrive".to_string(),
-                "Ec2".to_string(),
-                "Exoscale".to_string(),
-                "GCE".to_string(),
-                "NoCloud".to_string(),
-                "Oracle".to_string(),
-            ]
+            DEFAULT_DATASOURCE_LIST
+                .iter()
+                .map(|datasource| datasource.to_string())
+                .collect()
         })
     }
 
-    fn find_datasources_from_list(&mut self, input_datasource_list: Vec<String>) -> Vec<String> {
-        input_datasource_list
-            .into_iter()
-            .filter(|candidate_datasource| {
-                println!("{}", candidate_datasource);
-                match candidate_datasource.as_str() {
-                    // TEST GAP: These DSes have no tests: CloudStack, CloudSigma, Exoscale, MAAS
-                    "AliYun" => self.dscheck_AliYun(),
-                    "Azure" => self.dscheck_Azure(),
-                    "ConfigDrive" => self.dscheck_ConfigDrive(),
-                    "Ec2" => self.dscheck_Ec2(),
-                    "Exoscale" => self.dscheck_Exoscale(),
-                    "GCE" => self.dscheck_GCE(),
-                    "NoCloud" => self.dscheck_NoCloud(),
-                    "Oracle" => self.dscheck_Oracle(),
-                    _ => false,
-                }
-            })
+    // Datasources never to consider, from both disable_datasources in the cloud config (the
+    // latest definition, as with datasource_list) and ds-identify.cfg (e.g. "Ec2, AliYun")
+    fn get_disabled_datasources(&self) -> Vec<String> {
+        let mut list = None;
+        for config_path in self.get_cloud_config_paths() {
+            list = self
+                .get_list_from_path(&config_path, "disable_datasources")
+                .or(list);
+        }
+        let mut disabled_datasources = list.unwrap_or_default();
+        if let Some(cfg_list) = self.ds_identify_cfg.get("disable_datasources") {
+            disabled_datasources.extend(
+                cfg_list
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .split(',')
+                    .map(|datasource| datasource.trim().to_string())
+                    .filter(|datasource| !datasource.is_empty()),
+            );
+        }
+        disabled_datasources
+            .iter()
+            .map(|datasource| canonical_datasource_name(datasource))
             .collect()
     }
 
+    fn check_datasource(&mut self, datasource: &str) -> policy::CheckResult {
+        match datasource {
+            // TEST GAP: These DSes have no unit tests: Akamai, AltCloud, Azure, Bigstep,
+            // CloudSigma, CloudStack, DigitalOcean, Gandi, Hetzner, LXD, MAAS, OVF, OpenNebula,
+            // OpenStack, RbxCloud, Scaleway, SmartOS, TencentCloud, VMware, Vultr; CloudStack,
+            // CloudSigma and MAAS aren't covered by cloud-init's ds-identify tests either
+            "Akamai" => self.dscheck_Akamai().into(),
+            "AliYun" => self.dscheck_AliYun().into(),
+            "AltCloud" => self.dscheck_AltCloud().into(),
+            "Azure" => self.dscheck_Azure().into(),
+            "Bigstep" => self.dscheck_Bigstep().into(),
+            "CloudSigma" => self.dscheck_CloudSigma().into(),
+            "CloudStack" => self.dscheck_CloudStack(),
+            "ConfigDrive" => self.dscheck_ConfigDrive().into(),
+            "DigitalOcean" => self.dscheck_DigitalOcean().into(),
+            "Ec2" => self.dscheck_Ec2().into(),
+            "Exoscale" => self.dscheck_Exoscale().into(),
+            "GCE" => self.dscheck_GCE(),
+            "Gandi" => self.dscheck_Gandi().into(),
+            "Hetzner" => self.dscheck_Hetzner().into(),
+            "LXD" => self.dscheck_LXD().into(),
+            "MAAS" => self.dscheck_MAAS().into(),
+            "NWCS" => self.dscheck_NWCS().into(),
+            "NoCloud" => self.dscheck_NoCloud().into(),
+            "OVF" => self.dscheck_OVF().into(),
+            "OpenNebula" => self.dscheck_OpenNebula().into(),
+            "OpenStack" => self.dscheck_OpenStack(),
+            "Oracle" => self.dscheck_Oracle().into(),
+            "RbxCloud" => self.dscheck_RbxCloud().into(),
+            "Scaleway" => self.dscheck_Scaleway().into(),
+            "SmartOS" => self.dscheck_SmartOS().into(),
+            "TencentCloud" => self.dscheck_TencentCloud().into(),
+            "UpCloud" => self.dscheck_UpCloud().into(),
+            "VMware" => self.dscheck_VMware().into(),
+            "Vultr" => self.dscheck_Vultr().into(),
+            _ => policy::CheckResult::NotFound,
+        }
+    }
+
+    fn find_datasources_from_list(
+        &mut self,
+        input_datasource_list: Vec<String>,
+        policy: &policy::Policy,
+    ) -> Vec<String> {
+        let mut found_datasources = vec![];
+        let mut maybe_datasources = vec![];
+        self.check_results.clear();
+        for candidate_datasource in input_datasource_list {
+            let result = self.check_datasource(&candidate_datasource);
+            println!("{}: {}", candidate_datasource, result);
+            self.check_results
+                .push((candidate_datasource.clone(), result));
+            match result {
+                policy::CheckResult::Found => {
+                    found_datasources.push(candidate_datasource);
+                    if policy.found == policy::Found::First {
+                        break;
+                    }
+                }
+                policy::CheckResult::Maybe => maybe_datasources.push(candidate_datasource),
+                policy::CheckResult::NotFound => {}
+            }
+        }
+        if found_datasources.is_empty() && policy.maybe == policy::Maybe::All {
+            return maybe_datasources;
+        }
+        found_datasources
+    }
+
     // Identify
-    fn identify(mut self) {
+    fn identify(mut self) -> i32 {
         // Identify!
-        let input_datasource_list = self.get_datasource_list();
+        let cloud_init_arg = cmdline::find_cloud_init_arg(self.get_kernel_cmdline());
+        if cloud_init_arg == Some(cmdline::CloudInitArg::Disabled) {
+            println!("cloud-init=disabled on the kernel command line; not writing config");
+            return EXIT_DISABLED;
+        }
+        let force_enabled = cloud_init_arg == Some(cmdline::CloudInitArg::Enabled);
+        if force_enabled {
+            println!("cloud-init=enabled on the kernel command line");
+        } else if self.disabled_marker_exists() {
+            println!("etc/cloud/cloud-init.disabled exists; not writing config");
+            return EXIT_DISABLED;
+        } else if self
+            .ds_identify_cfg
+            .get("disabled")
+            .is_some_and(|val| ["1", "true", "yes"].contains(&val.as_str()))
+        {
+            println!("disabled in etc/cloud/ds-identify.cfg; not writing config");
+            return EXIT_DISABLED;
+        }
+
+        if self.is_ibm_provisioning() {
+            println!("IBMCloud provisioning in progress; disabled for provisioning");
+            return EXIT_DISABLED;
+        }
+
+        self.policy = self.get_policy();
+        println!("policy: {}", self.policy);
+        match self.policy.mode {
+            policy::Mode::Disabled if !force_enabled => {
+                println!("mode=disabled; not writing config");
+                return EXIT_DISABLED;
+            }
+            // cloud-init will use its own datasource_list
+            policy::Mode::Enabled => {
+                println!("mode=enabled; not writing config");
+                return EXIT_FOUND;
+            }
+            _ => {}
+        }
+        // Reports never change whether cloud-init runs
+        let report = self.policy.mode == policy::Mode::Report;
 
-        let mut output_datasource_list = if input_datasource_list.len() == 1 {
+        let datasource_arg = match cmdline::find_datasource_arg(self.get_kernel_cmdline()) {
+            Some(datasource_arg) => Some((datasource_arg, "on the kernel command line")),
+            // As elsewhere, the environment overrides ds-identify.cfg
+            None => std::env::var("DI_DSNAME")
+                .ok()
+                .filter(|name| !name.is_empty())
+                .map(|name| (name, "by DI_DSNAME"))
+                .or_else(|| {
+                    self.ds_identify_cfg
+                        .get("datasource")
+                        .filter(|name| !name.is_empty())
+                        .map(|name| (name.to_string(), "in etc/cloud/ds-identify.cfg"))
+                })
+                .map(|(name, source)| {
+                    let datasource_arg = cmdline::DatasourceArg {
+                        name,
+                        options: vec![],
+                    };
+                    (datasource_arg, source)
+                })
+                .or_else(|| {
+                    self.find_oem_datasource_arg()
+                        .map(|datasource_arg| (datasource_arg, "in an SMBIOS OEM string"))
+                }),
+        };
+        if let Some((datasource_arg, source)) = datasource_arg {
+            let datasource = canonical_datasource_name(&datasource_arg.name);
+            println!("datasource {} specified {}", datasource, source);
+            for (key, value) in &datasource_arg.options {
+                println!("  {}={}", key, value);
+            }
+            let mut datasource_list = vec![datasource];
+            if datasource_list != ["None"] {
+                datasource_list.push("None".to_string());
+            }
+            self.write_cfg_out(datasource_list);
+            return EXIT_FOUND;
+        }
+
+        let mut input_datasource_list: Vec<String> = self
+            .get_datasource_list()
+            .iter()
+            .map(|datasource| canonical_datasource_name(datasource))
+            .collect();
+        let disabled_datasources = self.get_disabled_datasources();
+        let configured_datasource_count = input_datasource_list.len();
+        input_datasource_list.retain(|datasource| {
+            let disabled = disabled_datasources.contains(datasource);
+            if disabled {
+                println!(
+                    "{} is in disable_datasources; not considering it",
+                    datasource
+                );
+            }
+            !disabled
+        });
+
+        // Not even None, so there's nothing for cloud-init to run with
+        if input_datasource_list.is_empty() {
+            if configured_datasource_count > 0 {
+                println!(
+                    "datasource_list is empty after removing disable_datasources; cloud-init is \
+                     effectively disabled"
+                );
+            } else {
+                println!("datasource_list is empty; cloud-init is effectively disabled");
+            }
+            if report {
+                self.write_cfg_out(input_datasource_list);
+                return EXIT_FOUND;
+            }
+            return EXIT_EMPTY_LIST;
+        }
+
+        // An admin who configures only None has opted out of datasources entirely
+        if input_datasource_list == ["None"] {
+            println!("datasource_list is [ None ]; skipping all checks");
+            // cloud-init still has to run for the config to be used
+            self.write_cfg_out(input_datasource_list);
+            return EXIT_FOUND;
+        }
+
+        let policy = self.policy;
+        // Only a list configured with a single entry is trusted without checks; one whittled down
+        // by disable_datasources is still searched
+        let mut output_datasource_list = if configured_datasource_count == 1 {
             input_datasource_list
         } else {
-            self.find_datasources_from_list(input_datasource_list)
+            let mut found_datasources =
+                self.find_datasources_from_list(input_datasource_list.clone(), &policy);
+            // Config drives and cidata ISOs can be attached after we've started
+            if let Some(wait_for_seed) = self.wait_for_seed {
+                let deadline = Instant::now() + wait_for_seed;
+                while found_datasources.is_empty() && Instant::now() < deadline {
+                    println!("No datasource found yet; waiting for seed devices");
+                    std::thread::sleep(
+                        SEED_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())),
+                    );
+                    self.block_devices = None;
+                    found_datasources =
+                        self.find_datasources_from_list(input_datasource_list.clone(), &policy);
+                }
+            }
+            if found_datasources.is_empty() && self.is_hyperv_without_azure() {
+                let generation = if self.is_efi() { 2 } else { 1 };
+                println!(
+                    "Platform is Hyper-V generation {} (not Azure); no datasource applies",
+                    generation
+                );
+            }
+            found_datasources
         };
 
+        if output_datasource_list.is_empty()
+            && policy.notfound == policy::NotFound::Disabled
+            && !report
+        {
+            println!("No datasource found and notfound=disabled; not writing config");
+            return EXIT_DISABLED;
+        }
+
         if !output_datasource_list.contains(&"None".to_string()) {
             output_datasource_list.push("None".to_string());
         };
 
         // Persist
         self.write_cfg_out(output_datasource_list);
+        EXIT_FOUND
+    }
+}
+
+// Helpers
+
+// Kernel arguments are typically lowercase ("ds=nocloud"), and configs may use any case or an
+// alias, so match them against the datasources we know about to recover the name cloud-init
+// expects
+fn canonical_datasource_name(name: &str) -> String {
+    let lowercase_name = name.to_ascii_lowercase();
+    if lowercase_name == "none" {
+        return "None".to_string();
+    }
+    if let Some((_, datasource)) = DATASOURCE_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == lowercase_name)
+    {
+        return datasource.to_string();
+    }
+    DEFAULT_DATASOURCE_LIST
+        .iter()
+        .find(|datasource| datasource.to_ascii_lowercase() == lowercase_name)
+        .map(|datasource| datasource.to_string())
+        .unwrap_or_else(|| name.to_string())
+}
+
+fn sorted_dir_entries(path: &PathBuf) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match std::fs::read_dir(path) {
+        Err(_) => vec![],
+        Ok(read_dir) => read_dir
+            .filter_map(|dir_entry| dir_entry.ok().map(|dir_entry| dir_entry.path()))
+            .collect(),
+    };
+    paths.sort();
+    paths
+}
+
+// Reverse the byte order of the first three UUID fields, converting between the big- and
+// little-endian representations
+fn uuid_swap_endianness(uuid: &str) -> Option<String> {
+    let fields: Vec<&str> = uuid.split('-').collect();
+    if fields.len() != 5
+        || fields.iter().zip(&[8, 4, 4, 4, 12]).any(|(field, len)| {
+            field.len() != *len || !field.chars().all(|c| c.is_ascii_hexdigit())
+        })
+    {
+        return None;
     }
+    let swap = |field: &str| -> String {
+        (0..field.len())
+            .step_by(2)
+            .rev()
+            .map(|i| &field[i..i + 2])
+            .collect()
+    };
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        swap(fields[0]),
+        swap(fields[1]),
+        swap(fields[2]),
+        fields[3],
+        fields[4]
+    ))
 }
 
-// Datasource checks
+// Only the first `limit` bytes are searched; OVF transport media are small, so anything larger
+// is not worth reading in full
+fn file_contains_ignore_case(path: &PathBuf, needle: &str, limit: u64) -> bool {
+    let mut content = vec![];
+    if File::open(path)
+        .and_then(|file| file.take(limit).read_to_end(&mut content))
+        .is_err()
+    {
+        return false;
+    }
+    String::from_utf8_lossy(&content)
+        .to_ascii_lowercase()
+        .contains(&needle.to_ascii_lowercase())
+}
+
+// Query the VMware guestinfo table, preferring vmware-rpctool over vmtoolsd as upstream does
+fn vmware_guestinfo_get(key: &str) -> Option<String> {
+    let rpc_command = format!("info-get guestinfo.{}", key);
+    let output = Command::new("vmware-rpctool")
+        .arg(&rpc_command)
+        .output()
+        .or_else(|_| {
+            Command::new("vmtoolsd")
+                .args(["--cmd", &rpc_command])
+                .output()
+        })
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
 
 fn main() {
+    // Parse our arguments
+    let mut wait_for_seed = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wait-for-seed" => match args.next().and_then(|secs| secs.parse::<u64>().ok()) {
+                Some(secs) => wait_for_seed = Some(Duration::from_secs(secs)),
+                None => {
+                    eprintln!("--wait-for-seed requires a number of seconds");
+                    std::process::exit(EXIT_USAGE);
+                }
+            },
+            _ => {
+                eprintln!("usage: rs-identify [--wait-for-seed <seconds>]");
+                std::process::exit(EXIT_USAGE);
+            }
+        }
+    }
+
     // Determine our paths/settings
-    let rs_identify = RsIdentify::from_env();
-    rs_identify.identify()
+    let rs_identify = RsIdentify::from_env(wait_for_seed);
+    std::process::exit(rs_identify.identify())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A scratch PATH_ROOT, removed when dropped
+    struct TestRoot {
+        path: PathBuf,
+    }
+
+    impl TestRoot {
+        fn new(name: &str) -> TestRoot {
+            let path = std::env::temp_dir().join(format!(
+                "rs-identify-test-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            create_dir_all(&path).unwrap();
+            TestRoot { path }
+        }
+
+        fn write(&self, relative_path: &str, content: &str) {
+            let path = self.path.join(relative_path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        fn rs_identify(&self) -> RsIdentify {
+            RsIdentify::new(
+                self.path.clone(),
+                true,
+                None,
+                None,
+                None,
+                probe::ProbePolicy::default(),
+                false,
+            )
+        }
+    }
+
+    impl Drop for TestRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn aliyun_seed_directory_is_found() {
+        let root = TestRoot::new("aliyun-seed");
+        root.write("var/lib/cloud/seed/AliYun/meta-data", "");
+        assert!(!root.rs_identify().dscheck_AliYun());
+        root.write("var/lib/cloud/seed/AliYun/user-data", "");
+        assert!(root.rs_identify().dscheck_AliYun());
+    }
+
+    #[test]
+    fn exoscale_seed_directory_is_found() {
+        let root = TestRoot::new("exoscale-seed");
+        root.write("var/lib/cloud/seed/Exoscale/meta-data", "");
+        assert!(!root.rs_identify().dscheck_Exoscale());
+        root.write("var/lib/cloud/seed/Exoscale/user-data", "");
+        assert!(root.rs_identify().dscheck_Exoscale());
+    }
+
+    #[test]
+    fn canonical_datasource_name_ignores_case() {
+        assert_eq!(canonical_datasource_name("ec2"), "Ec2");
+        assert_eq!(canonical_datasource_name("EC2"), "Ec2");
+        assert_eq!(canonical_datasource_name("Ec2"), "Ec2");
+        assert_eq!(canonical_datasource_name("nocloud"), "NoCloud");
+        assert_eq!(canonical_datasource_name("NONE"), "None");
+        assert_eq!(canonical_datasource_name("none"), "None");
+    }
+
+    #[test]
+    fn canonical_datasource_name_resolves_aliases() {
+        for (alias, datasource) in DATASOURCE_ALIASES {
+            assert_eq!(canonical_datasource_name(alias), *datasource);
+            assert_eq!(
+                canonical_datasource_name(&alias.to_ascii_uppercase()),
+                *datasource
+            );
+        }
+        assert_eq!(canonical_datasource_name("Ali-Yun"), "AliYun");
+        assert_eq!(canonical_datasource_name("AzureNet"), "Azure");
+        assert_eq!(canonical_datasource_name("Linode"), "Akamai");
+        assert_eq!(canonical_datasource_name("NoCloud-Net"), "NoCloud");
+    }
+
+    #[test]
+    fn stray_files_in_cloud_cfg_d_are_ignored() {
+        let root = TestRoot::new("cloud-cfg-d-stray");
+        root.write(
+            "etc/cloud/cloud.cfg.d/90_dpkg.cfg",
+            "datasource_list: [Ec2]\n",
+        );
+        root.write(
+            "etc/cloud/cloud.cfg.d/90_dpkg.cfg.dpkg-old",
+            "datasource_list: [Azure]\n",
+        );
+        root.write(
+            "etc/cloud/cloud.cfg.d/90_dpkg.cfg~",
+            "datasource_list: [GCE]\n",
+        );
+        assert_eq!(root.rs_identify().get_datasource_list(), vec!["Ec2"]);
+    }
+
+    #[test]
+    fn non_regular_files_in_cloud_cfg_d_are_skipped() {
+        let root = TestRoot::new("cloud-cfg-d-non-regular");
+        root.write(
+            "etc/cloud/cloud.cfg.d/90_dpkg.cfg",
+            "datasource_list: [Ec2]\n",
+        );
+        let cloud_cfg_d = root.path.join("etc/cloud/cloud.cfg.d");
+        create_dir_all(cloud_cfg_d.join("95_directory.cfg")).unwrap();
+        std::os::unix::fs::symlink(
+            cloud_cfg_d.join("nonexistent"),
+            cloud_cfg_d.join("96_dangling.cfg"),
+        )
+        .unwrap();
+        let rs_identify = root.rs_identify();
+        assert_eq!(
+            rs_identify.get_cloud_config_paths(),
+            vec![
+                root.path.join("etc/cloud/cloud.cfg"),
+                cloud_cfg_d.join("90_dpkg.cfg")
+            ]
+        );
+        assert_eq!(rs_identify.get_datasource_list(), vec!["Ec2"]);
+    }
+
+    #[test]
+    fn nocloud_smbios_seed_is_ignored_on_bare_metal() {
+        let root = TestRoot::new("nocloud-bare-metal");
+        root.write(
+            "sys/class/dmi/id/product_serial",
+            "ds=nocloud;s=http://10.0.0.1/\n",
+        );
+        root.write("proc/cpuinfo", "flags\t\t: fpu vme de pse tsc msr pae\n");
+        assert!(!root.rs_identify().dscheck_NoCloud());
+
+        // The same seed is trusted once we're a guest
+        root.write(
+            "proc/cpuinfo",
+            "flags\t\t: fpu vme de pse tsc msr pae hypervisor\n",
+        );
+        assert!(root.rs_identify().dscheck_NoCloud());
+    }
+
+    #[test]
+    fn ec2_is_not_found_in_containers_on_xen() {
+        let root = TestRoot::new("ec2-container-xen");
+        root.write("sys/hypervisor/type", "xen\n");
+        root.write(
+            "sys/hypervisor/uuid",
+            "ec2a1b2c-d3e4-f5a6-b7c8-d9e0f1a2b3c4\n",
+        );
+        assert!(root.rs_identify().dscheck_Ec2());
+        root.write("run/systemd/container", "lxc\n");
+        assert!(!root.rs_identify().dscheck_Ec2());
+    }
+
+    // DMI which satisfies the generic AWS heuristics
+    fn write_aws_serial_and_uuid(root: &TestRoot) {
+        let uuid = "ec2a1b2c-d3e4-f5a6-b7c8-d9e0f1a2b3c4\n";
+        root.write("sys/class/dmi/id/product_serial", uuid);
+        root.write("sys/class/dmi/id/product_uuid", uuid);
+    }
+
+    #[test]
+    fn samsung_cloud_platform_wins_over_aws() {
+        let root = TestRoot::new("ec2-samsung");
+        write_aws_serial_and_uuid(&root);
+        assert_eq!(root.rs_identify().ec2_identify_platform(), Some("AWS"));
+        root.write("sys/class/dmi/id/sys_vendor", "Samsung Cloud Platform\n");
+        assert_eq!(
+            root.rs_identify().ec2_identify_platform(),
+            Some("Samsung Cloud Platform")
+        );
+    }
+
+    #[test]
+    fn e24cloud_wins_over_aws() {
+        let root = TestRoot::new("ec2-e24cloud");
+        write_aws_serial_and_uuid(&root);
+        root.write("sys/class/dmi/id/sys_vendor", "e24cloud\n");
+        assert_eq!(root.rs_identify().ec2_identify_platform(), Some("E24cloud"));
+    }
+
+    #[test]
+    fn missing_dmi_fields_are_none() {
+        let root = TestRoot::new("dmi-missing");
+        root.write("sys/class/dmi/id/sys_vendor", "QEMU\n");
+        let mut rs_identify = root.rs_identify();
+        assert_eq!(rs_identify.dmi_sys_vendor(), &Some("QEMU".to_string()));
+        assert_eq!(rs_identify.dmi_board_vendor(), &None);
+        assert_eq!(rs_identify.dmi_chassis_vendor(), &None);
+        assert_eq!(rs_identify.dmi_board_asset_tag(), &None);
+    }
+
+    #[test]
+    fn board_and_chassis_vendors_identify_the_hypervisor() {
+        let root = TestRoot::new("dmi-virt-chassis");
+        root.write("sys/class/dmi/id/chassis_vendor", "QEMU\n");
+        assert!(root.rs_identify().get_virt() == &virt::Virt::Kvm);
+
+        let root = TestRoot::new("dmi-virt-board");
+        root.write("sys/class/dmi/id/board_vendor", "Microsoft Corporation\n");
+        assert!(root.rs_identify().get_virt() == &virt::Virt::HyperV);
+    }
+
+    #[test]
+    fn upcloud_is_found_by_sys_vendor() {
+        let root = TestRoot::new("upcloud");
+        assert!(!root.rs_identify().dscheck_UpCloud());
+        root.write("sys/class/dmi/id/sys_vendor", "UpCloud\n");
+        assert!(root.rs_identify().dscheck_UpCloud());
+    }
+
+    #[test]
+    fn nwcs_is_found_by_sys_vendor() {
+        let root = TestRoot::new("nwcs");
+        assert!(!root.rs_identify().dscheck_NWCS());
+        root.write("sys/class/dmi/id/sys_vendor", "NWCS\n");
+        assert!(root.rs_identify().dscheck_NWCS());
+    }
+
+    #[test]
+    fn nutanix_ahv_is_config_drive() {
+        let root = TestRoot::new("nutanix-ahv");
+        root.write("sys/class/dmi/id/product_name", "AHV\n");
+        assert!(!root.rs_identify().dscheck_ConfigDrive());
+        root.write("sys/class/dmi/id/sys_vendor", "Nutanix\n");
+        assert!(root.rs_identify().dscheck_ConfigDrive());
+    }
+
+    #[test]
+    fn gce_variants_are_maybe_without_probing() {
+        let variants = [
+            ("product_name", "Google Compute Engine"),
+            ("product_serial", "GoogleCloud-0123456789ABCDEF"),
+            ("sys_vendor", "Google"),
+            ("board_name", "Google Compute Engine"),
+        ];
+        for (field, value) in &variants {
+            let root = TestRoot::new(&format!("gce-{}", field));
+            root.write(&format!("sys/class/dmi/id/{}", field), value);
+            assert!(root.rs_identify().dscheck_GCE() == policy::CheckResult::Maybe);
+        }
+    }
+
+    #[test]
+    fn gce_product_name_and_serial_are_found() {
+        let root = TestRoot::new("gce-found");
+        assert!(root.rs_identify().dscheck_GCE() == policy::CheckResult::NotFound);
+        root.write("sys/class/dmi/id/product_name", "Google Compute Engine\n");
+        root.write(
+            "sys/class/dmi/id/product_serial",
+            "GoogleCloud-0123456789ABCDEF\n",
+        );
+        assert!(root.rs_identify().dscheck_GCE() == policy::CheckResult::Found);
+    }
+
+    #[test]
+    fn oracle_vm_shape_is_found_by_asset_tag() {
+        let root = TestRoot::new("oracle-vm");
+        root.write("sys/class/dmi/id/chassis_asset_tag", "OracleCloud.com\n");
+        assert!(root.rs_identify().dscheck_Oracle());
+    }
+
+    #[test]
+    fn oracle_bare_metal_shape_is_found_by_iscsi_root() {
+        let root = TestRoot::new("oracle-bm");
+        root.write("sys/class/dmi/id/sys_vendor", "Oracle Corporation\n");
+        root.write(
+            "proc/cmdline",
+            "root=UUID=1234 rd.iscsi.initiator=iqn.2015-02.oracle.boot:uefi\n",
+        );
+        assert!(!root.rs_identify().dscheck_Oracle());
+        root.write("run/net-ens3.conf", "DEVICE='ens3'\n");
+        assert!(root.rs_identify().dscheck_Oracle());
+    }
+
+    #[test]
+    fn uuid_swap_endianness_unswaps_byte_swapped_ec2_uuid() {
+        assert_eq!(
+            uuid_swap_endianness("45e12aec-dcd1-b213-94ed-01234567890a").as_deref(),
+            Some("ec2ae145-d1dc-13b2-94ed-01234567890a")
+        );
+    }
+
+    #[test]
+    fn uuid_swap_endianness_swaps_native_order_uuid() {
+        let uuid = "ec2ae145-d1dc-13b2-94ed-01234567890a";
+        let swapped = uuid_swap_endianness(uuid).unwrap();
+        assert_eq!(swapped, "45e12aec-dcd1-b213-94ed-01234567890a");
+        assert!(!swapped.starts_with("ec2"));
+        assert_eq!(uuid_swap_endianness(&swapped).as_deref(), Some(uuid));
+    }
+
+    #[test]
+    fn uuid_swap_endianness_rejects_malformed_input() {
+        assert_eq!(uuid_swap_endianness(""), None);
+        assert_eq!(uuid_swap_endianness("not-a-uuid"), None);
+        assert_eq!(
+            uuid_swap_endianness("45e12aec-dcd1-b213-94ed-01234567890"),
+            None
+        );
+        assert_eq!(
+            uuid_swap_endianness("45e12aec-dcd1-b213-94ed-01234567890a-00"),
+            None
+        );
+        assert_eq!(
+            uuid_swap_endianness("45e12aeg-dcd1-b213-94ed-01234567890a"),
+            None
+        );
+    }
+
+    #[test]
+    fn canonical_datasource_name_keeps_unknown_names() {
+        assert_eq!(canonical_datasource_name("MyCloud"), "MyCloud");
+    }
 }
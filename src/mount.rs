@@ -0,0 +1,67 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static MOUNT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// A read-only mount at a private directory, which is unmounted (and the directory removed) when
+// dropped, however the inspection ends
+struct Mount {
+    mount_point: PathBuf,
+}
+
+impl Mount {
+    fn new(device: &Path) -> Option<Mount> {
+        let mount_point = std::env::temp_dir().join(format!(
+            "rs-identify.{}.{}",
+            std::process::id(),
+            MOUNT_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&mount_point)
+            .ok()?;
+        let mounted = Command::new("mount")
+            .args(["-o", "ro,nosuid,nodev,noexec"])
+            .arg(device)
+            .arg(&mount_point)
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false);
+        if !mounted {
+            let _ = std::fs::remove_dir(&mount_point);
+            return None;
+        }
+        Some(Mount { mount_point })
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(&self.mount_point).output();
+        let _ = std::fs::remove_dir(&self.mount_point);
+    }
+}
+
+// Run `inspect` against the contents of a device.  Test trees can provide a directory in place
+// of the device, which is inspected as-is; real devices are only ever mounted when PATH_ROOT is
+// the real root.  None means the contents couldn't be looked at.
+pub fn with_mounted<T>(
+    device: &Path,
+    real_root: bool,
+    inspect: impl FnOnce(&Path) -> T,
+) -> Option<T> {
+    if device.is_dir() {
+        return Some(inspect(device));
+    }
+    if !real_root {
+        return None;
+    }
+    let mount = Mount::new(device)?;
+    Some(inspect(&mount.mount_point))
+}
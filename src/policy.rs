@@ -0,0 +1,141 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::fmt;
+
+// The outcome of a datasource check; "maybe" is for heuristics which couldn't be confirmed
+#[derive(Clone, Copy, PartialEq)]
+pub enum CheckResult {
+    Found,
+    Maybe,
+    NotFound,
+}
+
+impl From<bool> for CheckResult {
+    fn from(found: bool) -> CheckResult {
+        if found {
+            CheckResult::Found
+        } else {
+            CheckResult::NotFound
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckResult::Found => write!(f, "found"),
+            CheckResult::Maybe => write!(f, "maybe"),
+            CheckResult::NotFound => write!(f, "not found"),
+        }
+    }
+}
+
+// What to do at all: disable or enable cloud-init without any checks, search for datasources
+// and configure cloud-init with them, or search and only report the result
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Disabled,
+    Enabled,
+    Search,
+    Report,
+}
+
+// Which found datasources to use
+#[derive(Clone, Copy, PartialEq)]
+pub enum Found {
+    First,
+    All,
+}
+
+// Whether to fall back to "maybe" datasources when none are found outright
+#[derive(Clone, Copy, PartialEq)]
+pub enum Maybe {
+    All,
+    None,
+}
+
+// Whether cloud-init should run (with only the None datasource) when nothing is found
+#[derive(Clone, Copy, PartialEq)]
+pub enum NotFound {
+    Disabled,
+    Enabled,
+}
+
+// How to turn check results into a datasource_list, as upstream's "policy" setting, e.g.
+// "search,found=first,maybe=none,notfound=disabled"
+#[derive(Clone, Copy)]
+pub struct Policy {
+    pub mode: Mode,
+    pub found: Found,
+    pub maybe: Maybe,
+    pub notfound: NotFound,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            mode: Mode::Search,
+            found: Found::All,
+            maybe: Maybe::All,
+            notfound: NotFound::Enabled,
+        }
+    }
+}
+
+impl Policy {
+    // Apply the settings in a policy string over this one; on error, this policy is unchanged
+    pub fn apply(&mut self, policy: &str) -> Result<(), String> {
+        let mut updated = *self;
+        for token in policy
+            .split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+        {
+            match token {
+                "disabled" => updated.mode = Mode::Disabled,
+                "enabled" => updated.mode = Mode::Enabled,
+                "search" => updated.mode = Mode::Search,
+                "report" => updated.mode = Mode::Report,
+                "found=first" => updated.found = Found::First,
+                "found=all" => updated.found = Found::All,
+                "maybe=all" => updated.maybe = Maybe::All,
+                "maybe=none" => updated.maybe = Maybe::None,
+                "notfound=disabled" => updated.notfound = NotFound::Disabled,
+                "notfound=enabled" => updated.notfound = NotFound::Enabled,
+                _ => return Err(format!("invalid policy setting: {}", token)),
+            }
+        }
+        *self = updated;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Disabled => "disabled",
+            Mode::Enabled => "enabled",
+            Mode::Search => "search",
+            Mode::Report => "report",
+        };
+        let found = match self.found {
+            Found::First => "first",
+            Found::All => "all",
+        };
+        let maybe = match self.maybe {
+            Maybe::All => "all",
+            Maybe::None => "none",
+        };
+        let notfound = match self.notfound {
+            NotFound::Disabled => "disabled",
+            NotFound::Enabled => "enabled",
+        };
+        write!(
+            f,
+            "{},found={},maybe={},notfound={}",
+            mode, found, maybe, notfound
+        )
+    }
+}
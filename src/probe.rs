@@ -0,0 +1,84 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::fmt;
+#[cfg(feature = "network-probe")]
+use std::io::{Read, Write};
+#[cfg(feature = "network-probe")]
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// Whether metadata services may be queried, and how much boot time that's allowed to cost
+pub struct ProbePolicy {
+    pub enabled: bool,
+    // Per request
+    pub timeout: Duration,
+    // Across all requests
+    pub budget: Duration,
+    // For requests which get no response at all
+    pub retries: u32,
+}
+
+impl Default for ProbePolicy {
+    fn default() -> ProbePolicy {
+        ProbePolicy {
+            enabled: false,
+            timeout: Duration::from_secs(1),
+            budget: Duration::from_secs(5),
+            retries: 0,
+        }
+    }
+}
+
+impl fmt::Display for ProbePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.enabled {
+            return write!(f, "disabled");
+        }
+        write!(
+            f,
+            "enabled (timeout {:?}, budget {:?}, {} retries)",
+            self.timeout, self.budget, self.retries
+        )
+    }
+}
+
+// A response from a metadata service
+#[cfg(feature = "network-probe")]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+// Metadata services speak plain HTTP on port 80, so a minimal HTTP/1.0 client is all we need
+#[cfg(feature = "network-probe")]
+pub fn http_request(
+    method: &str,
+    host: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    timeout: Duration,
+) -> Option<Response> {
+    let address = (host, 80).to_socket_addrs().ok()?.next()?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    stream.set_write_timeout(Some(timeout)).ok()?;
+
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, host);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).ok()?;
+
+    let mut response = vec![];
+    stream.take(1024 * 1024).read_to_end(&mut response).ok()?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    let status = head.split_whitespace().nth(1)?.parse().ok()?;
+    Some(Response {
+        status,
+        body: body.to_string(),
+    })
+}
@@ -0,0 +1,44 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::path::Path;
+use std::process::Command;
+
+// The kernel's view of the system, as uname(1) would report it
+pub struct Uname {
+    pub kernel_name: String,
+    pub kernel_release: String,
+    pub kernel_version: String,
+    pub machine: String,
+}
+
+impl Uname {
+    // procfs is read so that tests can provide values under PATH_ROOT; uname itself is only
+    // run for anything missing there (e.g. "arch", which older kernels lack), and only when
+    // PATH_ROOT is the real root, so the host can't leak into tests
+    pub fn read(path_root: &Path) -> Uname {
+        let is_real_root = path_root == Path::new("/");
+        let field = |sysctl: &str, flag: &str| {
+            std::fs::read_to_string(path_root.join("proc/sys/kernel").join(sysctl))
+                .ok()
+                .or_else(|| is_real_root.then(|| run_uname(flag)).flatten())
+                .map(|value| value.trim().to_string())
+                .unwrap_or_default()
+        };
+        Uname {
+            kernel_name: field("ostype", "-s"),
+            kernel_release: field("osrelease", "-r"),
+            kernel_version: field("version", "-v"),
+            machine: field("arch", "-m"),
+        }
+    }
+}
+
+fn run_uname(flag: &str) -> Option<String> {
+    let output = Command::new("uname").arg(flag).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
@@ -0,0 +1,134 @@
+// Copyright 2020 Daniel Watkins
+//
+// Use of this source code is governed by the CNPLv4 license that can be found in LICENSE.txt
+
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+// The platform we're running on, named as systemd-detect-virt would
+#[derive(Clone, PartialEq)]
+pub enum Virt {
+    None,
+    Kvm,
+    Xen,
+    VMware,
+    HyperV,
+    Container(String),
+    Unknown,
+}
+
+impl fmt::Display for Virt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Virt::None => write!(f, "none"),
+            Virt::Kvm => write!(f, "kvm"),
+            Virt::Xen => write!(f, "xen"),
+            Virt::VMware => write!(f, "vmware"),
+            Virt::HyperV => write!(f, "hyperv"),
+            Virt::Container(container) => write!(f, "{}", container),
+            Virt::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// Hypervisor detection
+pub fn detect_xen(path_root: &Path) -> bool {
+    // Xen guests (PV and HVM alike) have xenfs mounted or report the hypervisor type in sysfs
+    let hypervisor_type = std::fs::read_to_string(path_root.join("sys/hypervisor/type"))
+        .map(|hypervisor_type| hypervisor_type.trim().to_string())
+        .ok();
+    path_root.join("proc/xen").exists() || hypervisor_type.as_deref() == Some("xen")
+}
+
+pub fn detect_hypervisor(path_root: &Path, dmi_values: &[String]) -> Virt {
+    if detect_xen(path_root) {
+        return Virt::Xen;
+    }
+
+    for dmi_value in dmi_values {
+        if dmi_value.starts_with("VMware") {
+            return Virt::VMware;
+        }
+        if *dmi_value == "Microsoft Corporation" {
+            return Virt::HyperV;
+        }
+        if dmi_value.starts_with("Xen") {
+            return Virt::Xen;
+        }
+        if dmi_value.starts_with("QEMU") || dmi_value.contains("KVM") || *dmi_value == "Amazon EC2"
+        {
+            return Virt::Kvm;
+        }
+    }
+
+    // Guests see the "hypervisor" CPU flag, so its absence means bare metal
+    match std::fs::read_to_string(path_root.join("proc/cpuinfo")) {
+        Err(_) => Virt::Unknown,
+        Ok(cpuinfo) => {
+            let has_hypervisor_flag = cpuinfo
+                .lines()
+                .filter(|line| line.starts_with("flags"))
+                .any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"));
+            if has_hypervisor_flag {
+                Virt::Unknown
+            } else {
+                Virt::None
+            }
+        }
+    }
+}
+
+// Container detection
+pub fn detect_container_systemd(systemd_detect_virt: &Path) -> Option<String> {
+    // systemd-detect-virt exits non-zero (and prints "none") outside of containers, so only its
+    // output is meaningful
+    let output = Command::new(systemd_detect_virt)
+        .arg("--container")
+        .output()
+        .ok()?;
+    let container = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    if container.is_empty() || container == "none" {
+        return None;
+    }
+    Some(container)
+}
+
+pub fn detect_container_environ(path_root: &Path) -> Option<String> {
+    // init's environment is NUL-separated, and container managers (lxc, docker, podman,
+    // systemd-nspawn) set container=<name> in it
+    let environ_path = path_root.join("proc/1/environ");
+    let environ = std::fs::read(&environ_path).ok()?;
+    environ
+        .split(|byte| *byte == 0)
+        .filter_map(|variable| std::str::from_utf8(variable).ok())
+        .find_map(|variable| variable.strip_prefix("container="))
+        .map(|container| container.trim().to_string())
+        .filter(|container| !container.is_empty())
+}
+
+pub fn detect_container_run_file(path_root: &Path) -> Option<String> {
+    // systemd records the container it was started in here, and unlike /proc/1/environ it's
+    // world-readable
+    let container_path = path_root.join("run/systemd/container");
+    std::fs::read_to_string(container_path)
+        .ok()
+        .map(|container| container.trim().to_string())
+        .filter(|container| !container.is_empty())
+}
+
+pub fn detect_container_markers(path_root: &Path) -> Option<String> {
+    // Runtimes which don't tell init about themselves still leave these behind; names match
+    // systemd-detect-virt's
+    if path_root.join("proc/vz").exists() && !path_root.join("proc/bc").exists() {
+        // /proc/bc exists on the OpenVZ host too, but not in its containers
+        return Some("openvz".to_string());
+    }
+    if path_root.join("run/.containerenv").exists() {
+        return Some("podman".to_string());
+    }
+    if path_root.join(".dockerenv").exists() {
+        return Some("docker".to_string());
+    }
+    None
+}